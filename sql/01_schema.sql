@@ -1,5 +1,12 @@
 use `isucari`;
 
+-- Versioned migrations under `migrations/` are re-applied on top of this schema.
+DROP TABLE IF EXISTS `_sqlx_migrations`;
+-- Created by migrations; dropped here so `/initialize` also logs everyone out and
+-- forgets login failures.
+DROP TABLE IF EXISTS `sessions`;
+DROP TABLE IF EXISTS `login_limits`;
+
 DROP TABLE IF EXISTS `configs`;
 CREATE TABLE configs (
     `name` VARCHAR(191) NOT NULL PRIMARY KEY,
@@ -0,0 +1,8 @@
+-- Timelines and the transaction list all page through `items` with
+-- `ORDER BY created_at DESC, id DESC` plus a keyset cursor on the same columns.
+-- `seller_id OR buyer_id` is served by an index merge over the two owner indexes.
+-- The category timeline gets its index along with `root_category_id` in 0002.
+ALTER TABLE `items`
+  ADD INDEX `idx_created_at_id` (`created_at`, `id`),
+  ADD INDEX `idx_seller_id_created_at_id` (`seller_id`, `created_at`, `id`),
+  ADD INDEX `idx_buyer_id_created_at_id` (`buyer_id`, `created_at`, `id`);
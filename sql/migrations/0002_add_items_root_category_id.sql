@@ -0,0 +1,33 @@
+-- Items carry their root category, like `transaction_evidences.item_root_category_id`,
+-- so the category timeline is a single range scan instead of `category_id IN (...)`.
+-- Nothing filters on `category_id` alone any more, so its index goes away.
+ALTER TABLE `items`
+  ADD COLUMN `root_category_id` int unsigned NOT NULL DEFAULT 0 AFTER `category_id`,
+  DROP INDEX `idx_category_id`,
+  ADD INDEX `idx_root_category_id_created_at_id` (`root_category_id`, `created_at`, `id`);
+
+UPDATE `items` i
+  JOIN `categories` c ON c.`id` = i.`category_id`
+  SET i.`root_category_id` = IF(c.`parent_id` = 0, c.`id`, c.`parent_id`);
+
+-- Selling inserts an item and editing may touch its category; deriving the column here
+-- keeps every writer consistent without having to repeat the lookup.
+CREATE TRIGGER `items_set_root_category_id_on_insert` BEFORE INSERT ON `items`
+  FOR EACH ROW
+  SET NEW.`root_category_id` = COALESCE(
+    (SELECT IF(`parent_id` = 0, `id`, `parent_id`) FROM `categories` WHERE `id` = NEW.`category_id`),
+    0
+  );
+
+-- Bumps, purchases and shipping updates leave the category alone, so only a changed
+-- `category_id` pays for the lookup.
+CREATE TRIGGER `items_set_root_category_id_on_update` BEFORE UPDATE ON `items`
+  FOR EACH ROW
+  SET NEW.`root_category_id` = IF(
+    NEW.`category_id` = OLD.`category_id`,
+    OLD.`root_category_id`,
+    COALESCE(
+      (SELECT IF(`parent_id` = 0, `id`, `parent_id`) FROM `categories` WHERE `id` = NEW.`category_id`),
+      0
+    )
+  );
@@ -0,0 +1,8 @@
+-- Persistent sessions for `session.store = "mysql"`. `session` is the serialized
+-- `tide::sessions::Session`; `expires` is NULL for sessions that never expire.
+CREATE TABLE `sessions` (
+  `id` varchar(64) NOT NULL PRIMARY KEY,
+  `session` text NOT NULL,
+  `expires` datetime(6) NULL,
+  INDEX `idx_expires` (`expires`)
+) ENGINE=InnoDB DEFAULT CHARACTER SET utf8mb4;
@@ -0,0 +1,4 @@
+-- Sessions remember the generation they were created in; `get_user` rejects sessions
+-- from an older one, so bumping it signs the user out everywhere.
+ALTER TABLE `users`
+  ADD COLUMN `session_generation` int unsigned NOT NULL DEFAULT 0 AFTER `last_bump`;
@@ -0,0 +1,9 @@
+-- Login rate limit state for `login_limit.backend = "mysql"`, one row per `ip:<addr>`
+-- or `account:<account_name>` key.
+CREATE TABLE `login_limits` (
+  `key` varchar(191) NOT NULL PRIMARY KEY,
+  `tokens` double NOT NULL,
+  `updated_at` datetime(6) NOT NULL,
+  `failures` int unsigned NOT NULL DEFAULT 0,
+  `locked_until` datetime(6) NULL
+) ENGINE=InnoDB DEFAULT CHARACTER SET utf8mb4;
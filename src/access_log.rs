@@ -0,0 +1,175 @@
+use crate::metrics::RoutePattern;
+use async_std::task_local;
+use chrono::{SecondsFormat, Utc};
+use rand::RngCore;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use tide::sessions::Session;
+use tide::{Middleware, Next, Request};
+
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Incoming ids longer than this, or with anything but `[A-Za-z0-9._-]`, are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+task_local! {
+    static CURRENT: RefCell<Option<RequestContext>> = RefCell::new(None);
+}
+
+/// What is known about the request being handled on this task. tide runs a request's
+/// middleware and handler on its connection's task, so code deep inside a handler can
+/// reach this without a `Request` at hand.
+struct RequestContext {
+    id: String,
+    db: Timing,
+    external: Timing,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Timing {
+    count: u32,
+    total: Duration,
+}
+
+impl Timing {
+    fn add(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+    }
+
+    fn millis(&self) -> f64 {
+        self.total.as_secs_f64() * 1000.0
+    }
+}
+
+fn with_current<T>(f: impl FnOnce(&mut RequestContext) -> T) -> Option<T> {
+    CURRENT
+        .try_with(|current| current.borrow_mut().as_mut().map(f))
+        .ok()
+        .flatten()
+}
+
+/// The id of the request being handled on this task, for outbound calls and error logs.
+pub(crate) fn current_request_id() -> Option<String> {
+    with_current(|context| context.id.clone())
+}
+
+/// Attributes a finished SQL statement to the current request.
+pub(crate) fn record_db_time(elapsed: Duration) {
+    with_current(|context| context.db.add(elapsed));
+}
+
+/// Attributes a finished payment or shipment call to the current request.
+pub(crate) fn record_external_time(elapsed: Duration) {
+    with_current(|context| context.external.add(elapsed));
+}
+
+#[derive(Serialize)]
+struct AccessLogLine<'a> {
+    time: String,
+    request_id: &'a str,
+    method: String,
+    path: String,
+    route: Option<&'static str>,
+    status: u16,
+    latency_ms: f64,
+    user_id: Option<String>,
+    bytes: Option<usize>,
+    db_ms: f64,
+    db_queries: u32,
+    external_ms: f64,
+    external_calls: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Writes one JSON line per request to stdout and echoes `X-Request-Id` on the response.
+/// With `server_timing` set, the DB and external call totals are also sent back as a
+/// `Server-Timing` header for browser devtools.
+///
+/// Must be registered after the session middleware so the user can be read.
+pub(crate) struct AccessLog {
+    pub(crate) server_timing: bool,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AccessLog {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let started = Instant::now();
+        let request_id = req
+            .header(REQUEST_ID_HEADER)
+            .map(|values| values.last().as_str().to_string())
+            .filter(|id| is_valid_request_id(id))
+            .unwrap_or_else(generate_request_id);
+        let method = req.method().to_string();
+        let path = req.url().path().to_string();
+        let user_id = req
+            .ext::<Session>()
+            .and_then(|session| session.get::<String>("user_id"));
+
+        let context = RequestContext {
+            id: request_id.clone(),
+            db: Timing::default(),
+            external: Timing::default(),
+        };
+        let previous = CURRENT.with(|current| current.replace(Some(context)));
+        let mut res = next.run(req).await;
+        let context = CURRENT.with(|current| current.replace(previous));
+        let (db, external) = context.map_or_else(Default::default, |c| (c.db, c.external));
+
+        res.insert_header(REQUEST_ID_HEADER, request_id.as_str());
+        if self.server_timing {
+            res.insert_header(
+                "Server-Timing",
+                format!(
+                    "db;desc=\"{} queries\";dur={:.3}, external;desc=\"{} calls\";dur={:.3}",
+                    db.count,
+                    db.millis(),
+                    external.count,
+                    external.millis()
+                ),
+            );
+        }
+        let status = res.status();
+        let line = AccessLogLine {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            request_id: &request_id,
+            method,
+            path,
+            route: res.ext::<RoutePattern>().map(|route| route.0),
+            status: status.into(),
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+            user_id,
+            bytes: res.len(),
+            db_ms: db.millis(),
+            db_queries: db.count,
+            external_ms: external.millis(),
+            external_calls: external.count,
+            error: res
+                .error()
+                .filter(|_| status.is_server_error())
+                .map(|e| e.to_string()),
+        };
+        if let Ok(mut json) = serde_json::to_vec(&line) {
+            json.push(b'\n');
+            let _ = io::stdout().lock().write_all(&json);
+        }
+        Ok(res)
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
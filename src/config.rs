@@ -0,0 +1,947 @@
+use crate::consts;
+use crate::listener::ListenAddr;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tide::StatusCode;
+
+/// Environment variables understood on top of the config file, and the key each one sets.
+/// The `MYSQL_*` names are shared with `sql/init.sh`.
+const ENV_VARS: &[(&str, &str)] = &[
+    ("MYSQL_HOST", "database.host"),
+    ("MYSQL_PORT", "database.port"),
+    ("MYSQL_USER", "database.user"),
+    ("MYSQL_PASS", "database.password"),
+    ("MYSQL_DBNAME", "database.dbname"),
+    ("MYSQL_MAX_CONNECTIONS", "database.max_connections"),
+    ("MYSQL_MIN_CONNECTIONS", "database.min_connections"),
+    ("MYSQL_ACQUIRE_TIMEOUT", "database.acquire_timeout_secs"),
+    ("MYSQL_IDLE_TIMEOUT", "database.idle_timeout_secs"),
+    ("MYSQL_MAX_LIFETIME", "database.max_lifetime_secs"),
+    ("ISUCARI_ENV", "server.environment"),
+    ("ISUCARI_LISTEN", "server.listen"),
+    ("ISUCARI_UPLOAD_DIR", "server.upload_dir"),
+    ("ISUCARI_SHUTDOWN_TIMEOUT", "server.shutdown_timeout_secs"),
+    ("PAYMENT_SERVICE_URL", "services.payment_url"),
+    ("SHIPMENT_SERVICE_URL", "services.shipment_url"),
+    ("ITEMS_PER_PAGE", "paging.items_per_page"),
+    ("TRANSACTION_PER_PAGE", "paging.transactions_per_page"),
+    ("ISUCARI_READYZ_CHECK_SERVICES", "health.check_services"),
+    ("ISUCARI_LOG_LEVEL", "log.level"),
+    ("ISUCARI_SLOW_QUERY_MS", "log.slow_query_ms"),
+    ("ISUCARI_TRACE_EXPORTER", "trace.exporter"),
+    ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "trace.otlp_endpoint"),
+    ("OTEL_SERVICE_NAME", "trace.service_name"),
+    ("ISUCARI_DEBUG", "debug.enabled"),
+    ("ISUCARI_SESSION_STORE", "session.store"),
+    ("ISUCARI_SESSION_SECRET", "session.secret"),
+    (
+        "ISUCARI_SESSION_PREVIOUS_SECRETS",
+        "session.previous_secrets",
+    ),
+    ("ISUCARI_SECRETS_SOURCE", "secrets.source"),
+    ("ISUCARI_SECRETS_PATH", "secrets.path"),
+    ("ISUCARI_TLS_CERT_FILE", "tls.cert_file"),
+    ("ISUCARI_TLS_KEY_FILE", "tls.key_file"),
+];
+
+/// Session cookies are signed with keys derived from the secret, which needs this much.
+const MIN_SECRET_LEN: usize = 32;
+
+const CONFIG_FILE_ENV: &str = "ISUCARI_CONFIG";
+
+const USAGE: &str = "\
+usage: isucon9-rust [--config <path>] [--print-config] [--<key> <value>]...
+
+Settings are layered as defaults < config file < environment < command line.
+Any key of the config file can be given as a flag, e.g. `--database.host db`.
+Lists such as `server.listen` take comma-separated values.";
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) database: DatabaseConfig,
+    pub(crate) services: ServicesConfig,
+    pub(crate) paging: PagingConfig,
+    pub(crate) health: HealthConfig,
+    pub(crate) log: LogConfig,
+    pub(crate) trace: TraceConfig,
+    pub(crate) debug: DebugConfig,
+    pub(crate) session: SessionConfig,
+    pub(crate) login_limit: LoginLimitConfig,
+    pub(crate) security_headers: SecurityHeadersConfig,
+    pub(crate) secrets: SecretsConfig,
+    pub(crate) tls: TlsConfig,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub(crate) environment: Environment,
+    /// See [`ListenAddr`] for the accepted forms. Served concurrently.
+    pub(crate) listen: Vec<String>,
+    pub(crate) upload_dir: PathBuf,
+    /// How long SIGTERM/SIGINT waits for in-flight requests before closing the pool.
+    pub(crate) shutdown_timeout_secs: u64,
+}
+
+/// `production` refuses settings that are only safe on a developer machine.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Environment {
+    Development,
+    Production,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DatabaseConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) user: String,
+    pub(crate) password: String,
+    pub(crate) dbname: String,
+    pub(crate) max_connections: u32,
+    pub(crate) min_connections: u32,
+    /// How long a handler waits for a free connection before failing.
+    pub(crate) acquire_timeout_secs: u64,
+    /// Idle connections above `min_connections` are closed after this long; 0 disables.
+    pub(crate) idle_timeout_secs: u64,
+    /// Connections are recycled after this long; 0 disables.
+    pub(crate) max_lifetime_secs: u64,
+    pub(crate) test_before_acquire: bool,
+}
+
+/// Fallbacks for when `/initialize` hasn't stored the service URLs yet.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServicesConfig {
+    pub(crate) payment_url: String,
+    pub(crate) shipment_url: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PagingConfig {
+    pub(crate) items_per_page: i32,
+    pub(crate) transactions_per_page: i32,
+}
+
+/// What `/readyz` checks besides MySQL and the categories table.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HealthConfig {
+    pub(crate) check_services: bool,
+    /// Budget for each individual check.
+    pub(crate) timeout_ms: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    /// One of `error`, `warn`, `info`, `debug`, `trace` or `off`.
+    pub(crate) level: String,
+    /// Statements running at least this long are logged with their SQL.
+    pub(crate) slow_query_ms: u64,
+    /// Send per-request DB and external call time as a `Server-Timing` header.
+    pub(crate) server_timing: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TraceConfig {
+    pub(crate) exporter: TraceExporter,
+    /// OTLP/HTTP traces endpoint of a collector, used with `exporter = "otlp"`.
+    pub(crate) otlp_endpoint: String,
+    /// Spans are appended here as OTLP JSON lines with `exporter = "file"`.
+    pub(crate) file: PathBuf,
+    pub(crate) service_name: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TraceExporter {
+    None,
+    Otlp,
+    File,
+}
+
+/// Profiling endpoints under `/debug/pprof`. Never enable these on a shared network.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DebugConfig {
+    pub(crate) enabled: bool,
+    pub(crate) max_profile_secs: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SessionConfig {
+    pub(crate) store: SessionStoreKind,
+    /// Signs session cookies and page cursors; at least 32 bytes.
+    pub(crate) secret: String,
+    /// Retired secrets whose cookies are still accepted, and re-signed with `secret`.
+    pub(crate) previous_secrets: Vec<String>,
+    /// Mark the cookie `Secure` even on plain HTTP requests, for when TLS ends at a
+    /// proxy in front. Requests made over HTTPS always get it.
+    pub(crate) cookie_secure: bool,
+    pub(crate) cookie_same_site: SameSitePolicy,
+    /// How often expired rows are deleted from the `sessions` table.
+    pub(crate) cleanup_interval_secs: u64,
+}
+
+/// Where sessions live: process memory (lost on restart), the `sessions` table, or an
+/// encrypted cookie holding the whole session.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SessionStoreKind {
+    Memory,
+    Mysql,
+    Cookie,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
+/// The certificate served on `tls:` listen addresses, re-read on SIGHUP.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub(crate) cert_file: PathBuf,
+    /// PEM private key matching the leaf certificate.
+    pub(crate) key_file: PathBuf,
+}
+
+/// Where the external service credentials are read from; see [`crate::secrets`].
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SecretsConfig {
+    pub(crate) source: SecretsSource,
+    /// The TOML file for `source = "file"`, or the directory for `source = "dir"`.
+    pub(crate) path: PathBuf,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SecretsSource {
+    Env,
+    File,
+    Dir,
+}
+
+/// Headers added to every response. Empty strings leave a header out.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SecurityHeadersConfig {
+    pub(crate) enabled: bool,
+    /// Empty means the built-in policy for the bundled frontend.
+    pub(crate) content_security_policy: String,
+    pub(crate) frame_options: String,
+    pub(crate) referrer_policy: String,
+    /// `Strict-Transport-Security` on HTTPS requests; 0 disables.
+    pub(crate) hsts_max_age_secs: u64,
+}
+
+/// Rate limits and lockout for `POST /login`. Off by default: the benchmarker logs in
+/// from a single IP far faster than any real client would.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LoginLimitConfig {
+    pub(crate) enabled: bool,
+    pub(crate) backend: LoginLimitBackend,
+    /// Attempts a client IP can make at once, and how fast that allowance refills.
+    pub(crate) ip_burst: u32,
+    pub(crate) ip_per_minute: u32,
+    /// The same per `account_name`, whichever IPs the attempts come from.
+    pub(crate) account_burst: u32,
+    pub(crate) account_per_minute: u32,
+    /// Consecutive failed logins after which the account is locked for `lockout_secs`.
+    pub(crate) lockout_failures: u32,
+    pub(crate) lockout_secs: u64,
+    /// Take the client IP from `Forwarded`/`X-Forwarded-For`. Only set this behind a
+    /// proxy that overwrites them, or clients can pick their own IP.
+    pub(crate) trust_forwarded: bool,
+}
+
+/// Where login limits are counted: per process, or in the `login_limits` table so all
+/// instances share them.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LoginLimitBackend {
+    Memory,
+    Mysql,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            environment: Environment::Development,
+            listen: vec!["127.0.0.1:8080".to_string()],
+            upload_dir: PathBuf::from("public/upload"),
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            host: "127.0.0.1".to_string(),
+            port: 3306,
+            user: "isucari".to_string(),
+            password: "isucari".to_string(),
+            dbname: "isucari".to_string(),
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            idle_timeout_secs: 600,
+            max_lifetime_secs: 1800,
+            test_before_acquire: true,
+        }
+    }
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        ServicesConfig {
+            payment_url: consts::DEFAULT_PAYMENT_SERVICE_URL.to_string(),
+            shipment_url: consts::DEFAULT_SHIPMENT_SERVICE_URL.to_string(),
+        }
+    }
+}
+
+impl Default for PagingConfig {
+    fn default() -> Self {
+        PagingConfig {
+            items_per_page: consts::ITEMS_PER_PAGE,
+            transactions_per_page: consts::TRANSACTION_PER_PAGE,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            check_services: false,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            slow_query_ms: 100,
+            server_timing: false,
+        }
+    }
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            exporter: TraceExporter::None,
+            otlp_endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+            file: PathBuf::from("traces.jsonl"),
+            service_name: "isucari".to_string(),
+        }
+    }
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        DebugConfig {
+            enabled: false,
+            max_profile_secs: 60,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            store: SessionStoreKind::Memory,
+            secret: consts::DEFAULT_SESSION_SECRET.to_string(),
+            previous_secrets: Vec::new(),
+            cookie_secure: false,
+            cookie_same_site: SameSitePolicy::Strict,
+            cleanup_interval_secs: 600,
+        }
+    }
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        SecretsConfig {
+            source: SecretsSource::Env,
+            path: PathBuf::new(),
+        }
+    }
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            enabled: true,
+            content_security_policy: String::new(),
+            frame_options: "DENY".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            hsts_max_age_secs: 365 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for LoginLimitConfig {
+    fn default() -> Self {
+        LoginLimitConfig {
+            enabled: false,
+            backend: LoginLimitBackend::Memory,
+            ip_burst: 100,
+            ip_per_minute: 600,
+            account_burst: 10,
+            account_per_minute: 10,
+            lockout_failures: 10,
+            lockout_secs: 300,
+            trust_forwarded: false,
+        }
+    }
+}
+
+impl FromStr for TraceExporter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TraceExporter::None),
+            "otlp" => Ok(TraceExporter::Otlp),
+            "file" => Ok(TraceExporter::File),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for Environment {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "development" => Ok(Environment::Development),
+            "production" => Ok(Environment::Production),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SessionStoreKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(SessionStoreKind::Memory),
+            "mysql" => Ok(SessionStoreKind::Mysql),
+            "cookie" => Ok(SessionStoreKind::Cookie),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SameSitePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(SameSitePolicy::Strict),
+            "lax" => Ok(SameSitePolicy::Lax),
+            "none" => Ok(SameSitePolicy::None),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for SecretsSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "env" => Ok(SecretsSource::Env),
+            "file" => Ok(SecretsSource::File),
+            "dir" => Ok(SecretsSource::Dir),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SecretsSource {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            SecretsSource::Env => "env",
+            SecretsSource::File => "file",
+            SecretsSource::Dir => "dir",
+        }
+    }
+}
+
+impl FromStr for LoginLimitBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(LoginLimitBackend::Memory),
+            "mysql" => Ok(LoginLimitBackend::Mysql),
+            _ => Err(()),
+        }
+    }
+}
+
+impl LogConfig {
+    pub(crate) fn level_filter(&self) -> LevelFilter {
+        self.level.parse().unwrap_or(LevelFilter::Info)
+    }
+}
+
+impl DatabaseConfig {
+    pub(crate) fn url(&self) -> String {
+        format!(
+            "mysql://{}:{}@{}:{}/{}?charset=utf8mb4&parseTime=true&loc=Local",
+            self.user, self.password, self.host, self.port, self.dbname
+        )
+    }
+}
+
+/// What `main` should do after the command line has been read.
+pub(crate) enum Command {
+    Serve(Config),
+    PrintConfig(Config),
+}
+
+impl Config {
+    /// Merges defaults, the config file, environment variables and command line flags.
+    pub(crate) fn load() -> tide::Result<Command> {
+        let args = parse_args(env::args().skip(1))?;
+        Config::layered(args, |var| env::var(var).ok())
+    }
+
+    /// [`load`](Self::load) with the environment looked up through `var`.
+    fn layered(args: Args, var: impl Fn(&str) -> Option<String>) -> tide::Result<Command> {
+        let file = args
+            .file
+            .clone()
+            .or_else(|| var(CONFIG_FILE_ENV).map(PathBuf::from));
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+
+        for &(name, key) in ENV_VARS {
+            if let Some(value) = var(name) {
+                config
+                    .set(key, &value)
+                    .map_err(|e| config_error(format!("environment variable {}: {}", name, e)))?;
+            }
+        }
+
+        for (key, value) in &args.overrides {
+            config
+                .set(key, value)
+                .map_err(|e| config_error(format!("--{}: {}\n\n{}", key, e, USAGE)))?;
+        }
+
+        config.validate()?;
+
+        if args.print_config {
+            Ok(Command::PrintConfig(config))
+        } else {
+            Ok(Command::Serve(config))
+        }
+    }
+
+    fn from_file(path: &Path) -> tide::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| config_error(format!("failed to read {}: {}", path.display(), e)))?;
+        toml::from_str(&text)
+            .map_err(|e| config_error(format!("failed to parse {}: {}", path.display(), e)))
+    }
+
+    /// Overrides a single setting addressed by its dotted key, e.g. `database.port`.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "server.environment" => self.server.environment = parse(value)?,
+            "server.listen" => {
+                self.server.listen = value.split(',').map(|s| s.trim().to_string()).collect()
+            }
+            "server.upload_dir" => self.server.upload_dir = PathBuf::from(value),
+            "server.shutdown_timeout_secs" => self.server.shutdown_timeout_secs = parse(value)?,
+            "database.host" => self.database.host = value.to_string(),
+            "database.port" => self.database.port = parse(value)?,
+            "database.user" => self.database.user = value.to_string(),
+            "database.password" => self.database.password = value.to_string(),
+            "database.dbname" => self.database.dbname = value.to_string(),
+            "database.max_connections" => self.database.max_connections = parse(value)?,
+            "database.min_connections" => self.database.min_connections = parse(value)?,
+            "database.acquire_timeout_secs" => self.database.acquire_timeout_secs = parse(value)?,
+            "database.idle_timeout_secs" => self.database.idle_timeout_secs = parse(value)?,
+            "database.max_lifetime_secs" => self.database.max_lifetime_secs = parse(value)?,
+            "database.test_before_acquire" => self.database.test_before_acquire = parse(value)?,
+            "services.payment_url" => self.services.payment_url = value.to_string(),
+            "services.shipment_url" => self.services.shipment_url = value.to_string(),
+            "paging.items_per_page" => self.paging.items_per_page = parse(value)?,
+            "paging.transactions_per_page" => self.paging.transactions_per_page = parse(value)?,
+            "health.check_services" => self.health.check_services = parse(value)?,
+            "health.timeout_ms" => self.health.timeout_ms = parse(value)?,
+            "log.level" => self.log.level = value.to_string(),
+            "log.slow_query_ms" => self.log.slow_query_ms = parse(value)?,
+            "log.server_timing" => self.log.server_timing = parse(value)?,
+            "trace.exporter" => self.trace.exporter = parse(value)?,
+            "trace.otlp_endpoint" => self.trace.otlp_endpoint = value.to_string(),
+            "trace.file" => self.trace.file = PathBuf::from(value),
+            "trace.service_name" => self.trace.service_name = value.to_string(),
+            "debug.enabled" => self.debug.enabled = parse(value)?,
+            "debug.max_profile_secs" => self.debug.max_profile_secs = parse(value)?,
+            "session.store" => self.session.store = parse(value)?,
+            "session.secret" => self.session.secret = value.to_string(),
+            "session.previous_secrets" => {
+                self.session.previous_secrets = value
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            }
+            "session.cleanup_interval_secs" => self.session.cleanup_interval_secs = parse(value)?,
+            "session.cookie_secure" => self.session.cookie_secure = parse(value)?,
+            "session.cookie_same_site" => self.session.cookie_same_site = parse(value)?,
+            "security_headers.enabled" => self.security_headers.enabled = parse(value)?,
+            "security_headers.content_security_policy" => {
+                self.security_headers.content_security_policy = value.to_string()
+            }
+            "security_headers.frame_options" => {
+                self.security_headers.frame_options = value.to_string()
+            }
+            "security_headers.referrer_policy" => {
+                self.security_headers.referrer_policy = value.to_string()
+            }
+            "security_headers.hsts_max_age_secs" => {
+                self.security_headers.hsts_max_age_secs = parse(value)?
+            }
+            "secrets.source" => self.secrets.source = parse(value)?,
+            "secrets.path" => self.secrets.path = PathBuf::from(value),
+            "tls.cert_file" => self.tls.cert_file = PathBuf::from(value),
+            "tls.key_file" => self.tls.key_file = PathBuf::from(value),
+            "login_limit.enabled" => self.login_limit.enabled = parse(value)?,
+            "login_limit.backend" => self.login_limit.backend = parse(value)?,
+            "login_limit.ip_burst" => self.login_limit.ip_burst = parse(value)?,
+            "login_limit.ip_per_minute" => self.login_limit.ip_per_minute = parse(value)?,
+            "login_limit.account_burst" => self.login_limit.account_burst = parse(value)?,
+            "login_limit.account_per_minute" => self.login_limit.account_per_minute = parse(value)?,
+            "login_limit.lockout_failures" => self.login_limit.lockout_failures = parse(value)?,
+            "login_limit.lockout_secs" => self.login_limit.lockout_secs = parse(value)?,
+            "login_limit.trust_forwarded" => self.login_limit.trust_forwarded = parse(value)?,
+            _ => return Err(format!("unknown setting `{}`", key)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> tide::Result<()> {
+        if self.server.listen.is_empty() {
+            return Err(config_error("server.listen needs at least one address"));
+        }
+        let listen_addrs = self.listen_addrs()?;
+        if listen_addrs.iter().any(ListenAddr::is_tls)
+            && (self.tls.cert_file.as_os_str().is_empty()
+                || self.tls.key_file.as_os_str().is_empty())
+        {
+            return Err(config_error(
+                "tls: listen addresses need tls.cert_file and tls.key_file",
+            ));
+        }
+        if self.database.max_connections == 0
+            || self.database.min_connections > self.database.max_connections
+        {
+            return Err(config_error(
+                "database.min_connections must not exceed a non-zero database.max_connections",
+            ));
+        }
+        if self.database.acquire_timeout_secs == 0 {
+            return Err(config_error(
+                "database.acquire_timeout_secs must be positive",
+            ));
+        }
+        if self.log.level.parse::<LevelFilter>().is_err() {
+            return Err(config_error(format!(
+                "unknown log.level `{}`",
+                self.log.level
+            )));
+        }
+        let secrets =
+            || std::iter::once(&self.session.secret).chain(&self.session.previous_secrets);
+        if secrets().any(|secret| secret.len() < MIN_SECRET_LEN) {
+            return Err(config_error(format!(
+                "session secrets must be at least {} bytes",
+                MIN_SECRET_LEN
+            )));
+        }
+        if self.server.environment == Environment::Production
+            && secrets().any(|secret| secret == consts::DEFAULT_SESSION_SECRET)
+        {
+            return Err(config_error(
+                "the built-in session secret can't be used in production; set session.secret or ISUCARI_SESSION_SECRET",
+            ));
+        }
+        if self.session.cookie_same_site == SameSitePolicy::None && !self.session.cookie_secure {
+            return Err(config_error(
+                "session.cookie_same_site = \"none\" needs session.cookie_secure, or browsers drop the cookie",
+            ));
+        }
+        if self.session.cleanup_interval_secs == 0 {
+            return Err(config_error(
+                "session.cleanup_interval_secs must be positive",
+            ));
+        }
+        if self.secrets.source != SecretsSource::Env && self.secrets.path.as_os_str().is_empty() {
+            return Err(config_error(format!(
+                "secrets.source = \"{}\" needs secrets.path",
+                self.secrets.source.as_str()
+            )));
+        }
+        let login_limit = &self.login_limit;
+        if login_limit.ip_burst == 0
+            || login_limit.account_burst == 0
+            || login_limit.lockout_failures == 0
+        {
+            return Err(config_error(
+                "login_limit bursts and lockout_failures must be positive",
+            ));
+        }
+        if self.paging.items_per_page <= 0 || self.paging.transactions_per_page <= 0 {
+            return Err(config_error("page sizes must be positive"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn listen_addrs(&self) -> tide::Result<Vec<ListenAddr>> {
+        self.server
+            .listen
+            .iter()
+            .map(|addr| ListenAddr::parse(addr).map_err(config_error))
+            .collect()
+    }
+
+    /// TOML rendering for `--print-config`, with the database password and session
+    /// secrets masked.
+    pub(crate) fn to_toml(&self) -> tide::Result<String> {
+        let mut redacted = self.clone();
+        redacted.database.password = "********".to_string();
+        redacted.session.secret = "********".to_string();
+        for secret in &mut redacted.session.previous_secrets {
+            *secret = "********".to_string();
+        }
+        toml::to_string_pretty(&redacted)
+            .map_err(|e| config_error(format!("failed to render config: {}", e)))
+    }
+}
+
+struct Args {
+    file: Option<PathBuf>,
+    print_config: bool,
+    overrides: Vec<(String, String)>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> tide::Result<Args> {
+    let mut parsed = Args {
+        file: None,
+        print_config: false,
+        overrides: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| config_error(format!("unexpected argument `{}`\n\n{}", arg, USAGE)))?;
+        let (key, inline_value) = match flag.find('=') {
+            Some(i) => (&flag[..i], Some(flag[i + 1..].to_string())),
+            None => (flag, None),
+        };
+        match key {
+            "print-config" => parsed.print_config = true,
+            "help" => return Err(config_error(USAGE)),
+            _ => {
+                let value = match inline_value {
+                    Some(value) => value,
+                    None => args
+                        .next()
+                        .ok_or_else(|| config_error(format!("--{} needs a value", key)))?,
+                };
+                if key == "config" {
+                    parsed.file = Some(PathBuf::from(value));
+                } else {
+                    parsed.overrides.push((key.to_string(), value));
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{}`", value))
+}
+
+fn config_error(message: impl Into<String>) -> tide::Error {
+    tide::Error::from_str(StatusCode::InternalServerError, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> tide::Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn serve(command: Command) -> Config {
+        match command {
+            Command::Serve(config) => config,
+            Command::PrintConfig(_) => panic!("expected Command::Serve"),
+        }
+    }
+
+    #[test]
+    fn parse_args_forms() {
+        let parsed = args(&[
+            "--config",
+            "isucari.toml",
+            "--database.host=db",
+            "--database.port",
+            "3307",
+            "--print-config",
+            "--session.secret=a=b",
+        ])
+        .unwrap();
+        assert_eq!(parsed.file, Some(PathBuf::from("isucari.toml")));
+        assert!(parsed.print_config);
+        assert_eq!(
+            parsed.overrides,
+            vec![
+                ("database.host".to_string(), "db".to_string()),
+                ("database.port".to_string(), "3307".to_string()),
+                ("session.secret".to_string(), "a=b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_args_errors() {
+        assert!(args(&["database.host"]).is_err());
+        assert!(args(&["--database.host"]).is_err());
+        assert!(args(&["--help"]).is_err());
+    }
+
+    #[test]
+    fn layers_in_order() {
+        let path = env::temp_dir().join(format!("isucari-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[database]\nhost = \"file\"\nuser = \"file\"\ndbname = \"file\"\n",
+        )
+        .unwrap();
+        let env_vars = [
+            (CONFIG_FILE_ENV, path.display().to_string()),
+            ("MYSQL_USER", "env".to_string()),
+            ("MYSQL_DBNAME", "env".to_string()),
+        ];
+        let var = |name: &str| {
+            env_vars
+                .iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        let config =
+            serve(Config::layered(args(&["--database.dbname", "cli"]).unwrap(), var).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.database.password, DatabaseConfig::default().password);
+        assert_eq!(config.database.host, "file");
+        assert_eq!(config.database.user, "env");
+        assert_eq!(config.database.dbname, "cli");
+    }
+
+    #[test]
+    fn print_config_masks_secrets() {
+        let mut config = Config::default();
+        config.database.password = "db-password".to_string();
+        config.session.secret = "current-session-secret".repeat(2);
+        config.session.previous_secrets = vec!["previous-session-secret".repeat(2)];
+        let command = Config::layered(args(&["--print-config"]).unwrap(), |_| None).unwrap();
+        assert!(matches!(command, Command::PrintConfig(_)));
+
+        let toml = config.to_toml().unwrap();
+        assert!(!toml.contains("db-password"));
+        assert!(!toml.contains("session-secret"));
+        assert!(toml.contains("********"));
+    }
+
+    /// Every key of the config file must be settable by flag and environment, so `set`
+    /// can't fall behind the structs.
+    #[test]
+    fn set_covers_every_key() {
+        fn keys(prefix: &str, value: &toml::Value, out: &mut Vec<(String, String)>) {
+            match value {
+                toml::Value::Table(table) => {
+                    for (name, value) in table {
+                        let key = if prefix.is_empty() {
+                            name.clone()
+                        } else {
+                            format!("{}.{}", prefix, name)
+                        };
+                        keys(&key, value, out);
+                    }
+                }
+                toml::Value::String(s) => out.push((prefix.to_string(), s.clone())),
+                toml::Value::Array(items) => {
+                    let items: Vec<_> = items.iter().filter_map(toml::Value::as_str).collect();
+                    out.push((prefix.to_string(), items.join(",")));
+                }
+                other => out.push((prefix.to_string(), other.to_string())),
+            }
+        }
+
+        let defaults = Config::default();
+        let mut settings = Vec::new();
+        keys(
+            "",
+            &toml::Value::try_from(&defaults).unwrap(),
+            &mut settings,
+        );
+        let mut config = Config::default();
+        for (key, value) in &settings {
+            config
+                .set(key, value)
+                .unwrap_or_else(|e| panic!("{} = {:?}: {}", key, value, e));
+        }
+        assert_eq!(
+            toml::to_string(&config).unwrap(),
+            toml::to_string(&defaults).unwrap()
+        );
+
+        for (var, key) in ENV_VARS {
+            assert!(
+                settings.iter().any(|(setting, _)| setting == key),
+                "{} sets unknown key {}",
+                var,
+                key
+            );
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use rand::RngCore;
+use tide::http::mime;
+use tide::{Middleware, Next, Request, StatusCode};
+
+const CSRF_TOKEN_FIELD: &str = "csrf_token";
+const CSRF_TOKEN_BYTES: usize = 20;
+
+/// Rejects the request with 422 unless its `csrf_token`, from the JSON body or the
+/// multipart form, matches the one in the session. Attached per route in `main`.
+///
+/// Like the reference implementation, this runs before the user is looked up, so a
+/// request without a session and without a token passes through to a 404. A logged-in
+/// session without a token is refused outright: the empty token would match anything.
+pub(crate) struct CsrfProtection;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for CsrfProtection {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let session = req.session();
+        let expected: Option<String> = session.get(CSRF_TOKEN_FIELD);
+        if expected.is_none() && session.get::<String>("user_id").is_some() {
+            return Err(csrf_error());
+        }
+        let expected = expected.unwrap_or_default();
+
+        // Handlers read the body themselves, so it's put back once the token is found.
+        let body = req.body_bytes().await?;
+        let token = match req.content_type() {
+            Some(content_type) if content_type.essence() == mime::MULTIPART_FORM.essence() => {
+                content_type.param("boundary").and_then(|boundary| {
+                    multipart_field(&body, boundary.as_str(), CSRF_TOKEN_FIELD)
+                })
+            }
+            _ => serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|json| json.get(CSRF_TOKEN_FIELD)?.as_str().map(String::from)),
+        };
+        req.set_body(body);
+
+        if !constant_time_eq(token.unwrap_or_default().as_bytes(), expected.as_bytes()) {
+            return Err(csrf_error());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+fn csrf_error() -> tide::Error {
+    tide::Error::from_str(StatusCode::UnprocessableEntity, "csrf token error")
+}
+
+/// A fresh token for a new login, hex like the reference implementation's.
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; CSRF_TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The value of the form field `name` in a `multipart/form-data` body.
+fn multipart_field(body: &[u8], boundary: &str, name: &str) -> Option<String> {
+    let delimiter = format!("--{}", boundary);
+    let disposition = format!("name=\"{}\"", name);
+    // Anything before the first delimiter is preamble, not a part.
+    let value = split(body, delimiter.as_bytes()).skip(1).find_map(|part| {
+        let header_end = find(part, b"\r\n\r\n")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let is_field = headers.lines().any(|line| {
+            line.to_ascii_lowercase()
+                .starts_with("content-disposition:")
+                && line.split(';').any(|param| param.trim() == disposition)
+        });
+        if !is_field {
+            return None;
+        }
+        let value = &part[header_end + 4..];
+        let value = value.strip_suffix(b"\r\n").unwrap_or(value);
+        String::from_utf8(value.to_vec()).ok()
+    });
+    value
+}
+
+fn split<'a>(haystack: &'a [u8], delimiter: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+    let mut rest = Some(haystack);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        match find(current, delimiter) {
+            Some(i) => {
+                rest = Some(&current[i + delimiter.len()..]);
+                Some(&current[..i])
+            }
+            None => {
+                rest = None;
+                Some(current)
+            }
+        }
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+    use tide::sessions::Session;
+
+    const BOUNDARY: &str = "----isucari";
+
+    fn form(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+        body.into_bytes()
+    }
+
+    #[test]
+    fn field_first() {
+        let body = form(&[("csrf_token", "abc"), ("name", "chair"), ("price", "100")]);
+        assert_eq!(
+            multipart_field(&body, BOUNDARY, "csrf_token").as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn field_last() {
+        let body = form(&[("name", "chair"), ("price", "100"), ("csrf_token", "abc")]);
+        assert_eq!(
+            multipart_field(&body, BOUNDARY, "csrf_token").as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn missing_boundary() {
+        let body = form(&[("csrf_token", "abc")]);
+        assert_eq!(multipart_field(&body, "----other", "csrf_token"), None);
+    }
+
+    #[test]
+    fn ignores_similar_names() {
+        let body = form(&[("csrf_token_x", "abc"), ("x_csrf_token", "def")]);
+        assert_eq!(multipart_field(&body, BOUNDARY, "csrf_token"), None);
+    }
+
+    /// Puts a copy of the session on every request, in place of the session middleware.
+    struct WithSession(Session);
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for WithSession {
+        async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            req.set_ext(self.0.clone());
+            Ok(next.run(req).await)
+        }
+    }
+
+    async fn post_buy(session: Session, body: serde_json::Value) -> StatusCode {
+        let mut app = tide::new();
+        app.at("/buy")
+            .with(WithSession(session))
+            .with(CsrfProtection)
+            .post(|_| async { Ok("") });
+        let mut req =
+            tide::http::Request::new(Method::Post, Url::parse("http://localhost/buy").unwrap());
+        req.set_body(body);
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.status()
+    }
+
+    fn logged_in(token: Option<&str>) -> Session {
+        let mut session = Session::new();
+        session.insert("user_id", "1").unwrap();
+        if let Some(token) = token {
+            session.insert(CSRF_TOKEN_FIELD, token).unwrap();
+        }
+        session
+    }
+
+    #[async_std::test]
+    async fn refuses_logged_in_session_without_token() {
+        let status = post_buy(logged_in(None), serde_json::json!({ "item_id": 1 })).await;
+        assert_eq!(status, StatusCode::UnprocessableEntity);
+        let status = post_buy(logged_in(None), serde_json::json!({ "csrf_token": "" })).await;
+        assert_eq!(status, StatusCode::UnprocessableEntity);
+    }
+
+    #[async_std::test]
+    async fn checks_token_against_session() {
+        let token = generate_token();
+        let status = post_buy(
+            logged_in(Some(&token)),
+            serde_json::json!({ "csrf_token": token }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::Ok);
+        let status = post_buy(
+            logged_in(Some(&token)),
+            serde_json::json!({ "csrf_token": "forged" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UnprocessableEntity);
+    }
+
+    #[async_std::test]
+    async fn passes_anonymous_requests_through() {
+        let status = post_buy(Session::new(), serde_json::json!({})).await;
+        assert_eq!(status, StatusCode::Ok);
+    }
+}
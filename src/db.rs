@@ -0,0 +1,208 @@
+use crate::access_log::{current_request_id, record_db_time};
+use crate::config::DatabaseConfig;
+use crate::logging::log_slow_query;
+use crate::trace::{Span, SpanKind};
+use serde::Serialize;
+use sqlx::mysql::{MySql, MySqlPoolOptions};
+use sqlx::pool::PoolConnection;
+use sqlx::{MySqlPool, Transaction};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// At most one saturation warning per this interval, so a stampede doesn't flood the log.
+const SATURATION_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The MySQL pool plus the bookkeeping behind [`PoolStats`].
+///
+/// Handlers should take connections through [`acquire`](Self::acquire) and
+/// [`begin`](Self::begin) so waiters and timeouts are counted.
+#[derive(Clone)]
+pub(crate) struct Db {
+    pool: MySqlPool,
+    max_connections: u32,
+    min_connections: u32,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    waiters: AtomicUsize,
+    acquire_timeouts: AtomicU64,
+    last_saturation_log: AtomicU64,
+}
+
+/// Point-in-time view of the pool, served by `/diagnostics.json`.
+#[derive(Serialize)]
+pub(crate) struct PoolStats {
+    pub(crate) size: u32,
+    pub(crate) idle: usize,
+    pub(crate) in_use: u32,
+    pub(crate) waiters: usize,
+    pub(crate) max_connections: u32,
+    pub(crate) min_connections: u32,
+    pub(crate) acquire_timeouts: u64,
+}
+
+impl Db {
+    pub(crate) async fn connect(database: &DatabaseConfig) -> sqlx::Result<Self> {
+        let pool = MySqlPoolOptions::new()
+            .max_connections(database.max_connections)
+            .min_connections(database.min_connections)
+            .connect_timeout(Duration::from_secs(database.acquire_timeout_secs))
+            .idle_timeout(non_zero_secs(database.idle_timeout_secs))
+            .max_lifetime(non_zero_secs(database.max_lifetime_secs))
+            .test_before_acquire(database.test_before_acquire)
+            .connect(&database.url())
+            .await?;
+        Ok(Db {
+            pool,
+            max_connections: database.max_connections,
+            min_connections: database.min_connections,
+            counters: Arc::default(),
+        })
+    }
+
+    /// For queries run directly against the pool, e.g. one-off statements.
+    pub(crate) fn pool(&self) -> &MySqlPool {
+        &self.pool
+    }
+
+    pub(crate) async fn acquire(&self) -> sqlx::Result<PoolConnection<MySql>> {
+        self.track(self.pool.acquire()).await
+    }
+
+    pub(crate) async fn begin(&self) -> sqlx::Result<Transaction<'static, MySql>> {
+        self.track(self.pool.begin()).await
+    }
+
+    pub(crate) async fn close(&self) {
+        self.pool.close().await
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        let (size, idle) = size_and_idle(&self.pool);
+        PoolStats {
+            size,
+            idle,
+            in_use: size.saturating_sub(idle as u32),
+            waiters: self.counters.waiters.load(Ordering::Relaxed),
+            max_connections: self.max_connections,
+            min_connections: self.min_connections,
+            acquire_timeouts: self.counters.acquire_timeouts.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn track<T>(&self, acquire: impl Future<Output = sqlx::Result<T>>) -> sqlx::Result<T> {
+        let waiters = self.counters.waiters.fetch_add(1, Ordering::Relaxed);
+        if waiters > 0 {
+            self.log_if_saturated(waiters);
+        }
+        let started = Instant::now();
+        let result = acquire.await;
+        self.counters.waiters.fetch_sub(1, Ordering::Relaxed);
+
+        if let Err(sqlx::Error::PoolTimedOut) = result {
+            self.counters
+                .acquire_timeouts
+                .fetch_add(1, Ordering::Relaxed);
+            tide::log::error!(
+                "timed out after {:?} waiting for a database connection",
+                started.elapsed(),
+                { request_id: current_request_id().unwrap_or_default() }
+            );
+        }
+        result
+    }
+
+    fn log_if_saturated(&self, waiters: usize) {
+        let (size, idle) = size_and_idle(&self.pool);
+        if size < self.max_connections || idle > 0 {
+            return;
+        }
+
+        let now = epoch_millis();
+        let last = self.counters.last_saturation_log.load(Ordering::Relaxed);
+        if now.saturating_sub(last) < SATURATION_LOG_INTERVAL.as_millis() as u64 {
+            return;
+        }
+        if self
+            .counters
+            .last_saturation_log
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            tide::log::warn!(
+                "database pool saturated: {}/{} connections in use, {} waiting",
+                size,
+                self.max_connections,
+                waiters
+            );
+        }
+    }
+}
+
+/// Runs one statement, charging its time to the current request and trace, and logging
+/// it when it's slower than `log.slow_query_ms`. `run` gets `sql` back to build the query:
+///
+/// ```ignore
+/// let user: User = db::timed(USER_BY_ID_SQL, |sql| {
+///     sqlx::query_as(sql).bind(user_id).fetch_one(&mut conn)
+/// })
+/// .await?;
+/// ```
+pub(crate) async fn timed<'q, T, F, Fut>(sql: &'q str, run: F) -> sqlx::Result<T>
+where
+    F: FnOnce(&'q str) -> Fut,
+    Fut: Future<Output = sqlx::Result<T>>,
+{
+    let mut span = Span::child(summary(sql), SpanKind::Client);
+    span.set_attribute("db.system", "mysql");
+    span.set_attribute("db.statement", sql.trim());
+    let started = Instant::now();
+    let result = run(sql).await;
+    let elapsed = started.elapsed();
+    span.record_result(&result);
+    record_db_time(elapsed);
+    log_slow_query(sql, elapsed);
+    result
+}
+
+/// The first few words of a statement, e.g. `SELECT id, seller_id, buyer_id`, as a span name.
+fn summary(sql: &str) -> String {
+    sql.split_whitespace().take(4).collect::<Vec<_>>().join(" ")
+}
+
+fn non_zero_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+/// sqlx 0.4 keeps `size` and `num_idle` private and only reports them through `Debug`.
+fn size_and_idle(pool: &MySqlPool) -> (u32, usize) {
+    let debug = format!("{:?}", pool);
+    (
+        debug_field(&debug, "size").unwrap_or(0),
+        debug_field(&debug, "num_idle").unwrap_or(0),
+    )
+}
+
+fn debug_field<T: std::str::FromStr>(debug: &str, name: &str) -> Option<T> {
+    let start = debug.find(&format!("{}: ", name))? + name.len() + 2;
+    let rest = &debug[start..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+fn epoch_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -1,25 +1,155 @@
+use crate::access_log;
 use crate::consts;
+use crate::csrf;
+use crate::db::{self, PoolStats};
+use crate::item_query::ItemQuery;
+use crate::metrics;
 use crate::models::{
-    APIShipmentStatusReq, APIShipmentStatusRes, Category, Config, Item, ItemDetail, ItemSimple,
-    ReqInitialize, ResInitialize, ResNewItems, ResTransactions, Shipping, TransactionEvidence,
-    User, UserSimple,
+    APIShipmentStatusReq, APIShipmentStatusRes, Category, Config, ItemDetail, ItemSimple,
+    ReqInitialize, ResInitialize, ResNewItems, ResTransactions, Shipping, ShippingSimple,
+    TransactionEvidence, User, UserSimple,
 };
-use crate::AppState;
+use crate::pagination::PageQuery;
+use crate::secrets::Secret;
+use crate::trace::{self, Span, SpanKind};
+use crate::{run_migrations, AppState};
 use async_recursion::async_recursion;
+use async_std::fs;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::Deserialize;
-use sqlx::mysql::{MySql, MySqlRow};
+use serde::Serialize;
+use sqlx::mysql::MySql;
 use sqlx::Executor;
-use sqlx::Row as _;
 use std::env;
 use std::io::{self, Write};
+use std::path::{Component, Path};
 use std::process::Command;
-use tide::{Body, Result, StatusCode};
+use tide::http::mime;
+use tide::sessions::Session;
+use tide::{Body, Response, Result, StatusCode};
 
 type Request = tide::Request<AppState>;
 
+/// The frontend's entry point, also read by the security headers for its inline script.
+pub(crate) const INDEX_HTML: &str = include_str!("../public/index.html");
+
+// Statements run as-is by the handlers below; `tests::handler_queries` EXPLAINs them.
+const USER_BY_ID_SQL: &str = r"
+    SELECT
+        id,
+        account_name,
+        hashed_password,
+        address,
+        num_sell_items,
+        last_bump,
+        session_generation,
+        created_at
+    FROM `users`
+    WHERE `id` = ?
+    ";
+
+const SESSION_USER_SQL: &str = r"
+    SELECT
+        id,
+        account_name,
+        hashed_password,
+        address,
+        num_sell_items,
+        last_bump,
+        session_generation,
+        created_at
+    FROM `users`
+    WHERE `id` = ? AND `session_generation` = ?
+    ";
+
+const SESSION_GENERATION_BY_ID_SQL: &str =
+    "SELECT `session_generation` FROM `users` WHERE `id` = ?";
+
+const BUMP_SESSION_GENERATION_SQL: &str =
+    "UPDATE `users` SET `session_generation` = `session_generation` + 1 WHERE `id` = ?";
+
+const CATEGORY_BY_ID_SQL: &str = r"
+    SELECT
+        id,
+        parent_id,
+        category_name
+    FROM `categories`
+    WHERE `id` = ?
+    ";
+
+const UPSERT_CONFIG_SQL: &str = r"
+    INSERT INTO `configs` (`name`, `val`) VALUES (?, ?)
+    ON DUPLICATE KEY UPDATE `val` = VALUES(`val`)
+    ";
+
+const CONFIG_BY_NAME_SQL: &str = "SELECT name, val FROM `configs` WHERE `name` = ?";
+
+const TRANSACTION_EVIDENCE_BY_ITEM_ID_SQL: &str = r"
+    SELECT
+        id,
+        seller_id,
+        buyer_id,
+        status,
+        item_id,
+        item_name,
+        item_price,
+        item_description,
+        item_category_id,
+        item_root_category_id,
+        created_at,
+        updated_at
+    FROM `transaction_evidences`
+    WHERE `item_id` = ?
+    ";
+
+const TRANSACTION_EVIDENCE_BY_ID_SQL: &str = r"
+    SELECT
+        id,
+        seller_id,
+        buyer_id,
+        status,
+        item_id,
+        item_name,
+        item_price,
+        item_description,
+        item_category_id,
+        item_root_category_id,
+        created_at,
+        updated_at
+    FROM `transaction_evidences`
+    WHERE `id` = ?
+    ";
+
+const SHIPPING_SIMPLE_BY_TRANSACTION_EVIDENCE_ID_SQL: &str = r"
+    SELECT
+        transaction_evidence_id,
+        status,
+        reserve_id
+    FROM `shippings`
+    WHERE `transaction_evidence_id` = ?
+    ";
+
+const SHIPPING_BY_TRANSACTION_EVIDENCE_ID_SQL: &str = r"
+    SELECT
+        transaction_evidence_id,
+        status,
+        item_name,
+        item_id,
+        reserve_id,
+        reserve_time,
+        to_address,
+        to_name,
+        from_address,
+        from_name,
+        img_binary,
+        created_at,
+        updated_at
+    FROM `shippings`
+    WHERE `transaction_evidence_id` = ?
+    ";
+
 static JSON_PATH_PARAM_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(.+)\.json$").unwrap());
+static PNG_PATH_PARAM_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(.+)\.png$").unwrap());
 
 fn with_status<E>(status_code: StatusCode) -> impl FnOnce(E) -> tide::Error
 where
@@ -50,29 +180,22 @@ pub(crate) async fn post_initialize(mut req: Request) -> Result<Body> {
             .map_err(with_status(StatusCode::InternalServerError))?;
     }
 
-    let conn = &req.state().conn;
-    sqlx::query(
-        r"
-        INSERT INTO `configs` (`name`, `val`) VALUES (?, ?)
-        ON DUPLICATE KEY UPDATE `val` = VALUES(`val`)
-        ",
-    )
-    .bind("payment_service_url")
-    .bind(body.payment_service_url)
-    .execute(conn)
-    .await
-    .map_err(with_status(StatusCode::InternalServerError))?;
-    sqlx::query(
-        r"
-        INSERT INTO `configs` (`name`, `val`) VALUES (?, ?)
-        ON DUPLICATE KEY UPDATE `val` = VALUES(`val`)
-        ",
-    )
-    .bind("shipment_service_url")
-    .bind(body.shipment_service_url)
-    .execute(conn)
-    .await
-    .map_err(with_status(StatusCode::InternalServerError))?;
+    let conn = req.state().conn.pool();
+    run_migrations(conn)
+        .await
+        .map_err(with_status(StatusCode::InternalServerError))?;
+
+    let service_urls = vec![
+        ("payment_service_url", body.payment_service_url),
+        ("shipment_service_url", body.shipment_service_url),
+    ];
+    for (name, url) in service_urls {
+        db::timed(UPSERT_CONFIG_SQL, |sql| {
+            sqlx::query(sql).bind(name).bind(url).execute(conn)
+        })
+        .await
+        .map_err(with_status(StatusCode::InternalServerError))?;
+    }
 
     let res = ResInitialize {
         campaign: 0,
@@ -82,79 +205,17 @@ pub(crate) async fn post_initialize(mut req: Request) -> Result<Body> {
 }
 
 pub(crate) async fn get_new_items(req: Request) -> Result<Body> {
-    #[derive(Deserialize, Default)]
-    #[serde(default)]
-    struct Query {
-        item_id: Option<u64>,
-        created_at: Option<u64>,
-    }
-    let query: Query = req.query().map_err(with_status(StatusCode::BadRequest))?;
-    let Query {
-        item_id,
-        created_at,
-    } = query;
+    let paging = &req.state().paging;
+    let query: PageQuery = req.query().map_err(with_status(StatusCode::BadRequest))?;
+    let keyset = query.keyset(&paging.cursor_keys)?;
 
     let mut conn = req.state().conn.acquire().await?;
-    let items: Vec<Item> = match (item_id, created_at) {
-        (Some(item_id), Some(created_at)) => {
-            sqlx::query_as(
-                r"
-                SELECT 
-                    id,
-                    seller_id,
-                    buyer_id,
-                    status,
-                    name,
-                    price,
-                    description,
-                    image_name,
-                    category_id,
-                    created_at,
-                    updated_at
-                FROM `items`
-                WHERE `status` IN (?,?)
-                AND (`created_at` < ? OR (`created_at` <= ? AND `id` < ?))
-                ORDER BY `created_at` DESC, `id` DESC
-                LIMIT ?
-                ",
-            )
-            .bind(consts::ITEM_STATUS_ON_SALE)
-            .bind(consts::ITEM_STATUS_SOLD_OUT)
-            .bind(created_at)
-            .bind(created_at)
-            .bind(item_id)
-            .bind(consts::ITEMS_PER_PAGE + 1)
-            .fetch_all(&mut conn)
-            .await?
-        }
-        _ => {
-            sqlx::query_as(
-                r"
-                SELECT 
-                    id,
-                    seller_id,
-                    buyer_id,
-                    status,
-                    name,
-                    price,
-                    description,
-                    image_name,
-                    category_id,
-                    created_at,
-                    updated_at
-                FROM `items`
-                WHERE `status` IN (?,?)
-                ORDER BY `created_at` DESC, `id` DESC
-                LIMIT ?
-                ",
-            )
-            .bind(consts::ITEM_STATUS_ON_SALE)
-            .bind(consts::ITEM_STATUS_SOLD_OUT)
-            .bind(consts::ITEMS_PER_PAGE + 1)
-            .fetch_all(&mut conn)
-            .await?
-        }
-    };
+    let mut items = ItemQuery::new()
+        .status_in(&[consts::ITEM_STATUS_ON_SALE, consts::ITEM_STATUS_SOLD_OUT])
+        .page(keyset, paging.items_per_page + 1)
+        .fetch_all(&mut conn)
+        .await?;
+    let (has_next, next_cursor) = paging.finish_page(&mut items, paging.items_per_page);
 
     let mut item_simples = Vec::new();
     for item in items {
@@ -174,16 +235,11 @@ pub(crate) async fn get_new_items(req: Request) -> Result<Body> {
         });
     }
 
-    let mut has_next = false;
-    if item_simples.len() > consts::ITEMS_PER_PAGE as usize {
-        has_next = true;
-        item_simples.truncate(consts::ITEMS_PER_PAGE as usize);
-    }
-
     let res = ResNewItems {
         root_category_id: None,
         root_category_name: None,
         has_next,
+        next_cursor,
         items: item_simples,
     };
 
@@ -194,22 +250,9 @@ async fn get_user_simple_by_id<'e, E>(executor: &'e mut E, user_id: u64) -> Resu
 where
     &'e mut E: Executor<'e, Database = MySql>,
 {
-    let user: User = sqlx::query_as(
-        r"
-        SELECT
-            id,
-            account_name,
-            hashed_password,
-            address,
-            num_sell_items,
-            last_bump,
-            created_at
-        FROM `users`
-        WHERE `id` = ?
-        ",
-    )
-    .bind(user_id)
-    .fetch_one(executor)
+    let user: User = db::timed(USER_BY_ID_SQL, |sql| {
+        sqlx::query_as(sql).bind(user_id).fetch_one(executor)
+    })
     .await?;
 
     Ok(user.into())
@@ -221,18 +264,11 @@ where
     E: Send,
     for<'e> &'e mut E: Executor<'e, Database = MySql>,
 {
-    let mut category: Category = sqlx::query_as(
-        r"
-        SELECT
-            id,
-            parent_id,
-            category_name
-        FROM `categories`
-        WHERE `id` = ?
-        ",
-    )
-    .bind(category_id)
-    .fetch_one(&mut *executor)
+    let mut category: Category = db::timed(CATEGORY_BY_ID_SQL, |sql| {
+        sqlx::query_as(sql)
+            .bind(category_id)
+            .fetch_one(&mut *executor)
+    })
     .await?;
     if category.parent_id != 0 {
         category.parent_category_name = get_category_by_id(&mut *executor, category.parent_id)
@@ -265,95 +301,17 @@ pub(crate) async fn get_new_category_items(req: Request) -> Result<Body> {
         ));
     }
 
-    let category_ids = sqlx::query("SELECT id FROM `categories` WHERE parent_id=?")
-        .bind(root_category.id)
-        .try_map(|row: MySqlRow| {
-            let id = row.try_get::<u32, _>("id")?;
-            Ok(id.to_string())
-        })
+    let paging = &req.state().paging;
+    let query: PageQuery = req.query().map_err(with_status(StatusCode::BadRequest))?;
+    let keyset = query.keyset(&paging.cursor_keys)?;
+
+    let mut items = ItemQuery::new()
+        .status_in(&[consts::ITEM_STATUS_ON_SALE, consts::ITEM_STATUS_SOLD_OUT])
+        .root_category(root_category.id)
+        .page(keyset, paging.items_per_page + 1)
         .fetch_all(&mut conn)
-        .await?
-        .join(",");
-
-    #[derive(Deserialize, Default)]
-    #[serde(default)]
-    struct Query {
-        item_id: Option<u64>,
-        created_at: Option<u64>,
-    }
-    let query: Query = req.query().map_err(with_status(StatusCode::BadRequest))?;
-    let Query {
-        item_id,
-        created_at,
-    } = query;
-
-    let items: Vec<Item> = match (item_id, created_at) {
-        (Some(item_id), Some(created_at)) => {
-            // [How to bind Vec<i64> arguments for IN operator? · Issue #528 · launchbadge/sqlx](https://github.com/launchbadge/sqlx/issues/528)
-            let sql = format!(
-                r"
-                SELECT
-                    id,
-                    seller_id,
-                    buyer_id,
-                    status,
-                    name,
-                    price,
-                    description,
-                    image_name,
-                    category_id,
-                    created_at,
-                    updated_at
-                FROM `items`
-                WHERE `status` IN (?,?)
-                AND `category_id` IN ({})
-                AND (`created_at` < ? OR (`created_at` <= ? AND `id` < ?))
-                ORDER BY `created_at` DESC, `id` DESC
-                LIMIT ?
-                ",
-                &category_ids
-            );
-            sqlx::query_as(&sql)
-                .bind(consts::ITEM_STATUS_ON_SALE)
-                .bind(consts::ITEM_STATUS_SOLD_OUT)
-                .bind(created_at)
-                .bind(created_at)
-                .bind(item_id)
-                .bind(consts::ITEMS_PER_PAGE + 1)
-                .fetch_all(&mut conn)
-                .await?
-        }
-        _ => {
-            let sql = format!(
-                r"
-                SELECT
-                    id,
-                    seller_id,
-                    buyer_id,
-                    status,
-                    name,
-                    price,
-                    description,
-                    image_name,
-                    category_id,
-                    created_at,
-                    updated_at
-                FROM `items`
-                WHERE `status` IN (?,?)
-                AND category_id IN ({})
-                ORDER BY `created_at` DESC, `id` DESC
-                LIMIT ?
-                ",
-                &category_ids
-            );
-            sqlx::query_as(&sql)
-                .bind(consts::ITEM_STATUS_ON_SALE)
-                .bind(consts::ITEM_STATUS_SOLD_OUT)
-                .bind(consts::ITEMS_PER_PAGE + 1)
-                .fetch_all(&mut conn)
-                .await?
-        }
-    };
+        .await?;
+    let (has_next, next_cursor) = paging.finish_page(&mut items, paging.items_per_page);
 
     let mut item_simples = Vec::new();
     for item in items {
@@ -377,17 +335,12 @@ pub(crate) async fn get_new_category_items(req: Request) -> Result<Body> {
         })
     }
 
-    let mut has_next = false;
-    if item_simples.len() > consts::ITEMS_PER_PAGE as usize {
-        has_next = true;
-        item_simples.truncate(consts::ITEMS_PER_PAGE as usize);
-    }
-
     let res = ResNewItems {
         root_category_id: Some(root_category.id),
         root_category_name: Some(root_category.category_name),
         items: item_simples,
         has_next,
+        next_cursor,
     };
 
     Ok(Body::from_json(&res)?)
@@ -396,91 +349,25 @@ pub(crate) async fn get_new_category_items(req: Request) -> Result<Body> {
 pub(crate) async fn get_transactions(req: Request) -> Result<Body> {
     let user = get_user(&req).await?;
 
-    #[derive(Deserialize, Default)]
-    #[serde(default)]
-    struct Query {
-        item_id: Option<u64>,
-        created_at: Option<u64>,
-    }
-    let Query {
-        item_id,
-        created_at,
-    } = req.query().map_err(with_status(StatusCode::BadRequest))?;
+    let paging = &req.state().paging;
+    let query: PageQuery = req.query().map_err(with_status(StatusCode::BadRequest))?;
+    let keyset = query.keyset(&paging.cursor_keys)?;
 
     let mut tx = req.state().conn.begin().await?;
 
-    let items: Vec<Item> = match (item_id, created_at) {
-        (Some(item_id), Some(created_at)) => {
-            sqlx::query_as(
-                r"
-                SELECT 
-                    id,
-                    seller_id,
-                    buyer_id,
-                    status,
-                    name,
-                    price,
-                    description,
-                    image_name,
-                    category_id,
-                    created_at,
-                    updated_at
-                FROM `items`
-                WHERE (`seller_id` = ? OR `buyer_id` = ?)
-                AND `status` IN (?,?,?,?,?)
-                AND (`created_at` < ? OR (`created_at` <= ? AND `id` < ?))
-                ORDER BY `created_at` DESC, `id` DESC
-                LIMIT ?
-                ",
-            )
-            .bind(user.id)
-            .bind(user.id)
-            .bind(consts::ITEM_STATUS_ON_SALE)
-            .bind(consts::ITEM_STATUS_TRADING)
-            .bind(consts::ITEM_STATUS_SOLD_OUT)
-            .bind(consts::ITEM_STATUS_CANCEL)
-            .bind(consts::ITEM_STATUS_STOP)
-            .bind(created_at)
-            .bind(created_at)
-            .bind(item_id)
-            .bind(consts::TRANSACTION_PER_PAGE + 1)
-            .fetch_all(&mut tx)
-            .await?
-        }
-        _ => {
-            sqlx::query_as(
-                r"
-                SELECT 
-                    id,
-                    seller_id,
-                    buyer_id,
-                    status,
-                    name,
-                    price,
-                    description,
-                    image_name,
-                    category_id,
-                    created_at,
-                    updated_at
-                FROM `items`
-                WHERE (`seller_id` = ? OR `buyer_id` = ?)
-                AND `status` IN (?,?,?,?,?)
-                ORDER BY `created_at` DESC, `id` DESC
-                LIMIT ?
-                ",
-            )
-            .bind(user.id)
-            .bind(user.id)
-            .bind(consts::ITEM_STATUS_ON_SALE)
-            .bind(consts::ITEM_STATUS_TRADING)
-            .bind(consts::ITEM_STATUS_SOLD_OUT)
-            .bind(consts::ITEM_STATUS_CANCEL)
-            .bind(consts::ITEM_STATUS_STOP)
-            .bind(consts::TRANSACTION_PER_PAGE + 1)
-            .fetch_all(&mut tx)
-            .await?
-        }
-    };
+    let mut items = ItemQuery::new()
+        .owned_by(user.id)
+        .status_in(&[
+            consts::ITEM_STATUS_ON_SALE,
+            consts::ITEM_STATUS_TRADING,
+            consts::ITEM_STATUS_SOLD_OUT,
+            consts::ITEM_STATUS_CANCEL,
+            consts::ITEM_STATUS_STOP,
+        ])
+        .page(keyset, paging.transactions_per_page + 1)
+        .fetch_all(&mut tx)
+        .await?;
+    let (has_next, next_cursor) = paging.finish_page(&mut items, paging.transactions_per_page);
 
     let mut item_details: Vec<ItemDetail> = Vec::new();
     for item in items {
@@ -511,60 +398,27 @@ pub(crate) async fn get_transactions(req: Request) -> Result<Body> {
             item_detail.buyer = Some(buyer);
         }
 
-        let transaction_evidence: sqlx::Result<TransactionEvidence> = sqlx::query_as(
-            r"
-            SELECT
-                id,
-                seller_id,
-                buyer_id,
-                status,
-                item_id,
-                item_name,
-                item_price,
-                item_description,
-                item_category_id,
-                item_root_category_id,
-                created_at,
-                updated_at
-            FROM `transaction_evidences`
-            WHERE `item_id` = ?
-            ",
-        )
-        .bind(item.id)
-        .fetch_one(&mut tx)
-        .await;
+        let transaction_evidence: sqlx::Result<TransactionEvidence> =
+            db::timed(TRANSACTION_EVIDENCE_BY_ITEM_ID_SQL, |sql| {
+                sqlx::query_as(sql).bind(item_detail.id).fetch_one(&mut tx)
+            })
+            .await;
 
         match transaction_evidence {
             Ok(t) => {
-                let shipping: Shipping = sqlx::query_as(
-                    r"
-                    SELECT 
-                        transaction_evidence_id,
-                        status,
-                        item_name,
-                        item_id,
-                        reserve_id,
-                        reserve_time,
-                        to_address,
-                        to_name,
-                        from_address,
-                        from_name,
-                        img_binary,
-                        created_at,
-                        updated_at
-                    FROM `shippings`
-                    WHERE `transaction_evidence_id` = ?
-                    ",
-                )
-                .bind(t.id)
-                .fetch_one(&mut tx)
-                .await
-                .map_err(|e| match e {
-                    sqlx::Error::RowNotFound => tide::Error::new(StatusCode::NotFound, e),
-                    _ => tide::Error::new(StatusCode::InternalServerError, e),
-                })?;
+                let shipping: ShippingSimple =
+                    db::timed(SHIPPING_SIMPLE_BY_TRANSACTION_EVIDENCE_ID_SQL, |sql| {
+                        sqlx::query_as(sql).bind(t.id).fetch_one(&mut tx)
+                    })
+                    .await
+                    .map_err(|e| match e {
+                        sqlx::Error::RowNotFound => tide::Error::new(StatusCode::NotFound, e),
+                        _ => tide::Error::new(StatusCode::InternalServerError, e),
+                    })?;
                 let ssr = api_shipment_status(
-                    get_shipment_service_url(&mut tx).await,
+                    get_shipment_service_url(&mut tx, &req.state().config.services.shipment_url)
+                        .await,
+                    &req.state().secrets.shipment_api_token,
                     APIShipmentStatusReq {
                         reserve_id: shipping.reserve_id,
                     },
@@ -586,77 +440,89 @@ pub(crate) async fn get_transactions(req: Request) -> Result<Body> {
 
     tx.commit().await?;
 
-    let mut has_next = false;
-    if item_details.len() > consts::TRANSACTION_PER_PAGE as usize {
-        has_next = true;
-        item_details.truncate(consts::TRANSACTION_PER_PAGE as usize);
-    }
-
     let res = ResTransactions {
         has_next,
+        next_cursor,
         items: item_details,
     };
 
     Ok(Body::from_json(&res)?)
 }
 
+/// The logged-in user. Sessions from before the user's last `/sessions/revoke_all`
+/// are refused; a session without a generation counts as generation 0.
 async fn get_user(req: &Request) -> Result<User> {
     let session = req.session();
     let user_id: String = session
         .get("user_id")
         .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "no session"))?;
-    let conn = &req.state().conn;
-    let user: User = sqlx::query_as(
-        r"
-        SELECT 
-            id,
-            account_name,
-            hashed_password,
-            address,
-            num_sell_items,
-            last_bump,
-            created_at
-        FROM `users`
-        WHERE `id` = ?
-        ",
-    )
-    .bind(user_id)
-    .fetch_one(conn)
+    let generation: u32 = session.get("session_generation").unwrap_or(0);
+    let mut conn = req.state().conn.acquire().await?;
+    let user: Option<User> = db::timed(SESSION_USER_SQL, |sql| {
+        sqlx::query_as(sql)
+            .bind(user_id)
+            .bind(generation)
+            .fetch_optional(&mut conn)
+    })
     .await?;
-    Ok(user)
+    user.ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "no session"))
+}
+
+/// Logs `user` in on `session`. Login must go through here rather than setting
+/// `user_id` alone: without the user's current generation the session would be
+/// treated as generation 0 and refused after their first `/sessions/revoke_all`, and
+/// without a `csrf_token` every form post is refused.
+fn start_user_session(session: &mut Session, user: &User) -> Result<()> {
+    session.insert("user_id", user.id.to_string())?;
+    session.insert("session_generation", user.session_generation)?;
+    session.insert("csrf_token", csrf::generate_token())?;
+    Ok(())
 }
 
-async fn get_config_by_name<'e, E>(executor: &'e mut E, name: impl AsRef<str>) -> Result<String>
+pub(crate) async fn get_config_by_name<'e, E>(
+    executor: &'e mut E,
+    name: impl AsRef<str>,
+) -> Result<String>
 where
     &'e mut E: Executor<'e, Database = MySql>,
 {
-    let config: Config = sqlx::query_as("SELECT name, val FROM `configs` WHERE `name` = ?")
-        .bind(name.as_ref())
-        .fetch_one(executor)
-        .await?;
+    let config: Config = db::timed(CONFIG_BY_NAME_SQL, |sql| {
+        sqlx::query_as(sql).bind(name.as_ref()).fetch_one(executor)
+    })
+    .await?;
 
     Ok(config.val)
 }
 
-async fn get_shipment_service_url<'e, E>(executor: &'e mut E) -> String
+async fn get_shipment_service_url<'e, E>(executor: &'e mut E, default: &str) -> String
 where
     &'e mut E: Executor<'e, Database = MySql>,
 {
-    get_config_by_name(executor, "payment_service_url")
+    get_config_by_name(executor, "shipment_service_url")
         .await
-        .unwrap_or(consts::DEFAULT_SHIPMENT_SERVICE_URL.to_string())
+        .unwrap_or_else(|_| default.to_string())
 }
 
 async fn api_shipment_status(
     shipment_url: String,
+    token: &Secret,
     param: APIShipmentStatusReq,
 ) -> Result<APIShipmentStatusRes> {
-    let res = surf::get(format!("{}/status", shipment_url))
+    let mut req = surf::get(format!("{}/status", shipment_url))
         .body_json(&param)?
         .set_header("User-Agent", consts::USER_AGENT)
-        .set_header("Authorization", consts::ISUCARI_API_TOKEN)
-        .recv_json()
-        .await?;
+        .set_header("Authorization", format!("Bearer {}", token.expose()));
+    if let Some(request_id) = access_log::current_request_id() {
+        req = req.set_header(access_log::REQUEST_ID_HEADER, request_id);
+    }
+    let mut span = Span::child("shipment status", SpanKind::Client);
+    span.set_attribute("peer.service", "shipment");
+    if let Some(traceparent) = span.traceparent() {
+        req = req.set_header(trace::TRACEPARENT_HEADER, traceparent);
+    }
+    let res = metrics::outbound("shipment", "status", req.recv_json()).await;
+    span.record_result(&res);
+    let res = res?;
 
     Ok(res)
 }
@@ -666,37 +532,19 @@ pub(crate) async fn get_item(req: Request) -> Result<Body> {
     let item_id = JSON_PATH_PARAM_RE
         .captures(param.as_str())
         .and_then(|cap| cap.get(1))
-        .and_then(|it| it.as_str().parse::<u32>().ok())
+        .and_then(|it| it.as_str().parse::<u64>().ok())
         .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, "incorrect item id"))?;
 
     let user = get_user(&req).await?;
 
     let mut conn = req.state().conn.acquire().await?;
-    let item: Item = sqlx::query_as(
-        r"
-        SELECT
-            id,
-            seller_id,
-            buyer_id,
-            status,
-            name,
-            price,
-            description,
-            image_name,
-            category_id,
-            created_at,
-            updated_at
-        FROM `items`
-        WHERE `id` = ?
-        ",
-    )
-    .bind(item_id)
-    .fetch_one(&mut conn)
-    .await
-    .map_err(|e| match e {
-        sqlx::Error::RowNotFound => tide::Error::new(StatusCode::NotFound, e),
-        _ => tide::Error::new(StatusCode::InternalServerError, e),
-    })?;
+    let item = ItemQuery::by_id(item_id)
+        .fetch_one(&mut conn)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => tide::Error::new(StatusCode::NotFound, e),
+            _ => tide::Error::new(StatusCode::InternalServerError, e),
+        })?;
 
     let category = get_category_by_id(&mut conn, item.category_id).await?;
     let seller = get_user_simple_by_id(&mut conn, item.seller_id).await?;
@@ -725,28 +573,13 @@ pub(crate) async fn get_item(req: Request) -> Result<Body> {
         item_detail.buyer_id = Some(item.buyer_id);
         item_detail.buyer = Some(buyer);
 
-        let transaction_evidence: sqlx::Result<TransactionEvidence> = sqlx::query_as(
-            r"
-            SELECT
-                id,
-                seller_id,
-                buyer_id,
-                status,
-                item_id,
-                item_name,
-                item_price,
-                item_description,
-                item_category_id,
-                item_root_category_id,
-                created_at,
-                updated_at
-            FROM `transaction_evidence`
-            WHERE `item_id` = ?
-            ",
-        )
-        .bind(item.id)
-        .fetch_one(&mut conn)
-        .await;
+        let transaction_evidence: sqlx::Result<TransactionEvidence> =
+            db::timed(TRANSACTION_EVIDENCE_BY_ITEM_ID_SQL, |sql| {
+                sqlx::query_as(sql)
+                    .bind(item_detail.id)
+                    .fetch_one(&mut conn)
+            })
+            .await;
 
         match transaction_evidence {
             Err(sqlx::Error::RowNotFound) => {}
@@ -754,33 +587,15 @@ pub(crate) async fn get_item(req: Request) -> Result<Body> {
                 return Err(tide::Error::new(StatusCode::NotFound, e));
             }
             Ok(t) => {
-                let shipping: Shipping = sqlx::query_as(
-                    r"
-                    SELECT
-                        transaction_evidence_id,
-                        status,
-                        item_name,
-                        item_id,
-                        reserve_id,
-                        reserve_time,
-                        to_address,
-                        to_name,
-                        from_address,
-                        from_name,
-                        img_binary,
-                        created_at,
-                        updated_at
-                    FROM `shippings`
-                    WHERE `transaction_evidence_id` = ?
-                    ",
-                )
-                .bind(t.id)
-                .fetch_one(&mut conn)
-                .await
-                .map_err(|e| match e {
-                    sqlx::Error::RowNotFound => tide::Error::new(StatusCode::NotFound, e),
-                    _ => tide::Error::new(StatusCode::InternalServerError, e),
-                })?;
+                let shipping: ShippingSimple =
+                    db::timed(SHIPPING_SIMPLE_BY_TRANSACTION_EVIDENCE_ID_SQL, |sql| {
+                        sqlx::query_as(sql).bind(t.id).fetch_one(&mut conn)
+                    })
+                    .await
+                    .map_err(|e| match e {
+                        sqlx::Error::RowNotFound => tide::Error::new(StatusCode::NotFound, e),
+                        _ => tide::Error::new(StatusCode::InternalServerError, e),
+                    })?;
                 item_detail.transaction_evidence_id = Some(t.id);
                 item_detail.transaction_evidence_status = Some(t.status);
                 item_detail.shipping_status = Some(shipping.status);
@@ -815,8 +630,76 @@ pub(crate) async fn post_complete(req: Request) -> Result<Body> {
     todo!()
 }
 
-pub(crate) async fn get_qr_code(req: Request) -> Result<Body> {
-    todo!()
+pub(crate) async fn get_qr_code(req: Request) -> Result<Response> {
+    let param: String = req.param("transaction_evidence_id.png")?;
+    let transaction_evidence_id = PNG_PATH_PARAM_RE
+        .captures(param.as_str())
+        .and_then(|cap| cap.get(1))
+        .and_then(|it| it.as_str().parse::<u64>().ok())
+        .filter(|&id| id > 0)
+        .ok_or_else(|| {
+            tide::Error::from_str(StatusCode::BadRequest, "incorrect transaction_evidence id")
+        })?;
+
+    let seller = get_user(&req).await?;
+
+    let mut conn = req.state().conn.acquire().await?;
+    let transaction_evidence: TransactionEvidence =
+        db::timed(TRANSACTION_EVIDENCE_BY_ID_SQL, |sql| {
+            sqlx::query_as(sql)
+                .bind(transaction_evidence_id)
+                .fetch_one(&mut conn)
+        })
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => {
+                tide::Error::from_str(StatusCode::NotFound, "transaction_evidences not found")
+            }
+            _ => tide::Error::new(StatusCode::InternalServerError, e),
+        })?;
+
+    if transaction_evidence.seller_id != seller.id {
+        return Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            "権限がありません",
+        ));
+    }
+
+    // This is the only place that needs the QR code image, so the full row including
+    // `img_binary` is loaded here while list/detail pages use `ShippingSimple`.
+    let shipping: Shipping = db::timed(SHIPPING_BY_TRANSACTION_EVIDENCE_ID_SQL, |sql| {
+        sqlx::query_as(sql)
+            .bind(transaction_evidence.id)
+            .fetch_one(&mut conn)
+    })
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::RowNotFound => {
+            tide::Error::from_str(StatusCode::NotFound, "shippings not found")
+        }
+        _ => tide::Error::new(StatusCode::InternalServerError, e),
+    })?;
+
+    if shipping.status != consts::SHIPPINGS_STATUS_WAIT_PICKUP
+        && shipping.status != consts::SHIPPINGS_STATUS_SHIPPING
+    {
+        return Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            "qrcode not available",
+        ));
+    }
+
+    if shipping.img_binary.is_empty() {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            "empty qrcode image",
+        ));
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(shipping.img_binary);
+    res.set_content_type(mime::PNG);
+    Ok(res)
 }
 
 pub(crate) async fn post_bump(req: Request) -> Result<Body> {
@@ -835,23 +718,322 @@ pub(crate) async fn post_register(req: Request) -> Result<Body> {
     todo!()
 }
 
+/// Ends the current session.
+pub(crate) async fn post_logout(mut req: Request) -> Result<Body> {
+    req.session_mut().destroy();
+    Body::from_json(&serde_json::json!({}))
+}
+
+/// Signs the user out of every other session by moving them to a new session
+/// generation. The current session follows them there with a fresh CSRF token, which
+/// is returned as `csrf_token` for the client's next post.
+pub(crate) async fn post_revoke_all_sessions(mut req: Request) -> Result<Body> {
+    let mut user = get_user(&req).await?;
+    let mut tx = req.state().conn.begin().await?;
+    db::timed(BUMP_SESSION_GENERATION_SQL, |sql| {
+        sqlx::query(sql).bind(user.id).execute(&mut tx)
+    })
+    .await?;
+    let (generation,): (u32,) = db::timed(SESSION_GENERATION_BY_ID_SQL, |sql| {
+        sqlx::query_as(sql).bind(user.id).fetch_one(&mut tx)
+    })
+    .await?;
+    tx.commit().await?;
+
+    user.session_generation = generation;
+    start_user_session(req.session_mut(), &user)?;
+    let csrf_token: String = req.session().get("csrf_token").unwrap_or_default();
+    Body::from_json(&serde_json::json!({ "csrf_token": csrf_token }))
+}
+
 pub(crate) async fn get_reports(req: Request) -> Result<Body> {
     todo!()
 }
 
+pub(crate) async fn get_diagnostics(req: Request) -> Result<Body> {
+    #[derive(Serialize)]
+    struct Diagnostics {
+        pool: PoolStats,
+    }
+
+    Body::from_json(&Diagnostics {
+        pool: req.state().conn.stats(),
+    })
+}
+
 pub(crate) async fn get_index(_req: Request) -> Result<&'static str> {
-    let html = include_str!("../public/index.html");
-    Ok(html)
+    Ok(INDEX_HTML)
 }
 
 pub(crate) async fn get_assets(req: Request) -> Result<Body> {
-    let mut file_path = env::current_dir()?;
+    let root = env::current_dir()?.join("public");
     let path: String = req.param("path")?;
-    file_path.push("public");
-    file_path.push(path);
+    serve_file_under(&root, &path).await
+}
+
+pub(crate) async fn get_upload(req: Request) -> Result<Body> {
+    let root = &req.state().config.server.upload_dir;
+    let path: String = req.param("path")?;
+    serve_file_under(root, &path).await
+}
+
+/// Serves `root/path`, answering 404 for anything that isn't a regular file inside
+/// `root` once symlinks are resolved. Dotfiles and `..` are refused outright.
+async fn serve_file_under(root: &Path, path: &str) -> Result<Body> {
+    let not_found = || tide::Error::from_str(StatusCode::NotFound, "not found");
+
+    let relative = Path::new(path);
+    let plain = relative.components().all(|component| match component {
+        Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+    if !plain {
+        return Err(not_found());
+    }
 
-    let body = Body::from_file(&file_path)
+    let root = fs::canonicalize(root).await.map_err(|_| not_found())?;
+    let file_path = fs::canonicalize(root.join(relative))
         .await
-        .map_err(with_status(StatusCode::NotFound))?;
-    Ok(body)
+        .map_err(|_| not_found())?;
+    if !file_path.starts_with(&root) || !file_path.is_file().await {
+        return Err(not_found());
+    }
+    Body::from_file(&file_path).await.map_err(|_| not_found())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item_query::Keyset;
+    use sqlx::mysql::MySqlArguments;
+    use sqlx::{Arguments, Row as _};
+
+    fn bind<T>(value: T) -> MySqlArguments
+    where
+        T: for<'q> sqlx::Encode<'q, MySql> + sqlx::Type<MySql> + Send,
+    {
+        let mut args = MySqlArguments::default();
+        args.add(value);
+        args
+    }
+
+    /// Every handler query, bound with representative values, with its `LIMIT` if paged.
+    fn handler_queries() -> Vec<(&'static str, String, MySqlArguments, Option<i32>)> {
+        let keyset = Keyset::from_params(Some(1), Some(chrono::Utc::now().timestamp() as u64));
+        let timeline = || {
+            ItemQuery::new().status_in(&[consts::ITEM_STATUS_ON_SALE, consts::ITEM_STATUS_SOLD_OUT])
+        };
+        let transactions = || {
+            ItemQuery::new().owned_by(1).status_in(&[
+                consts::ITEM_STATUS_ON_SALE,
+                consts::ITEM_STATUS_TRADING,
+                consts::ITEM_STATUS_SOLD_OUT,
+                consts::ITEM_STATUS_CANCEL,
+                consts::ITEM_STATUS_STOP,
+            ])
+        };
+        let item_queries = vec![
+            (
+                "get_new_items",
+                timeline().page(None, consts::ITEMS_PER_PAGE + 1),
+            ),
+            (
+                "get_new_items (paged)",
+                timeline().page(keyset, consts::ITEMS_PER_PAGE + 1),
+            ),
+            (
+                "get_new_category_items",
+                timeline()
+                    .root_category(1)
+                    .page(None, consts::ITEMS_PER_PAGE + 1),
+            ),
+            (
+                "get_new_category_items (paged)",
+                timeline()
+                    .root_category(1)
+                    .page(keyset, consts::ITEMS_PER_PAGE + 1),
+            ),
+            (
+                "get_transactions",
+                transactions().page(None, consts::TRANSACTION_PER_PAGE + 1),
+            ),
+            (
+                "get_transactions (paged)",
+                transactions().page(keyset, consts::TRANSACTION_PER_PAGE + 1),
+            ),
+            ("item by id", ItemQuery::by_id(1)),
+        ];
+
+        let mut queries: Vec<_> = item_queries
+            .into_iter()
+            .map(|(name, query)| (name, query.to_sql(), query.arguments(), query.limit()))
+            .collect();
+        let by_key = vec![
+            ("user by id", USER_BY_ID_SQL.to_string(), bind(1)),
+            ("session user", SESSION_USER_SQL.to_string(), {
+                let mut args = bind(1);
+                args.add(0u32);
+                args
+            }),
+            (
+                "session generation",
+                SESSION_GENERATION_BY_ID_SQL.to_string(),
+                bind(1),
+            ),
+            (
+                "bump session generation",
+                BUMP_SESSION_GENERATION_SQL.to_string(),
+                bind(1),
+            ),
+            ("category by id", CATEGORY_BY_ID_SQL.to_string(), bind(1)),
+            (
+                "config by name",
+                CONFIG_BY_NAME_SQL.to_string(),
+                bind("shipment_service_url"),
+            ),
+            (
+                "transaction evidence by item",
+                TRANSACTION_EVIDENCE_BY_ITEM_ID_SQL.to_string(),
+                bind(1),
+            ),
+            (
+                "transaction evidence by id",
+                TRANSACTION_EVIDENCE_BY_ID_SQL.to_string(),
+                bind(1),
+            ),
+            (
+                "shipping by transaction evidence",
+                SHIPPING_SIMPLE_BY_TRANSACTION_EVIDENCE_ID_SQL.to_string(),
+                bind(1),
+            ),
+            (
+                "shipping with QR code by transaction evidence",
+                SHIPPING_BY_TRANSACTION_EVIDENCE_ID_SQL.to_string(),
+                bind(1),
+            ),
+        ];
+        queries.extend(
+            by_key
+                .into_iter()
+                .map(|(name, sql, args)| (name, sql, args, None)),
+        );
+        queries
+    }
+
+    /// Fails when any handler query falls back to a full table or full index scan.
+    ///
+    /// The unfiltered timeline has no range to scan: its plan walks `idx_created_at_id`
+    /// backwards and stops at the `LIMIT`, which EXPLAIN reports as `type=index`. That
+    /// passes as long as the key is one of the `…_created_at_id` ordering indexes and
+    /// the estimated rows stay within the `LIMIT`.
+    ///
+    /// Needs a migrated database with the benchmark data loaded (`sql/init.sh`), since
+    /// MySQL happily scans near-empty tables. Run it with
+    /// `DATABASE_URL=mysql://... cargo test -- --ignored`.
+    #[async_std::test]
+    #[ignore = "needs DATABASE_URL pointing at MySQL with the benchmark data"]
+    async fn handler_queries_use_indexes() -> Result<()> {
+        let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = sqlx::MySqlPool::connect(&url).await?;
+        run_migrations(&pool).await?;
+
+        let mut full_scans = Vec::new();
+        for (name, sql, args, limit) in handler_queries() {
+            let explain = format!("EXPLAIN {}", sql);
+            for row in sqlx::query_with(&explain, args).fetch_all(&pool).await? {
+                let table: Option<String> = row.try_get("table")?;
+                let access_type: Option<String> = row.try_get("type")?;
+                let key: Option<String> = row.try_get("key")?;
+                // Signed or unsigned depending on the server version.
+                let rows: Option<u64> = row.try_get_unchecked("rows")?;
+                let ordered_walk = key.is_some_and(|key| key.ends_with("created_at_id"))
+                    && matches!((rows, limit), (Some(rows), Some(limit)) if rows <= limit as u64);
+                let full_scan = match access_type.as_deref() {
+                    Some("ALL") => true,
+                    Some("index") => !ordered_walk,
+                    _ => false,
+                };
+                if full_scan {
+                    full_scans.push(format!("{}: {}", name, table.unwrap_or_default()));
+                }
+            }
+        }
+
+        assert!(full_scans.is_empty(), "full scans: {:?}", full_scans);
+        Ok(())
+    }
+
+    /// `public/` with a file, a dotfile, a subdirectory and a symlink to `secret.txt`,
+    /// which sits next to `public/`. Removed on drop.
+    struct PublicDir(std::path::PathBuf);
+
+    impl PublicDir {
+        fn new(name: &str) -> Self {
+            let base = env::temp_dir().join(format!("isucari-{}-{}", name, std::process::id()));
+            let public = base.join("public");
+            std::fs::create_dir_all(public.join("static")).unwrap();
+            std::fs::write(public.join("index.txt"), "index").unwrap();
+            std::fs::write(public.join(".env"), "SECRET=1").unwrap();
+            std::fs::write(base.join("secret.txt"), "secret").unwrap();
+            std::os::unix::fs::symlink(base.join("secret.txt"), public.join("link.txt")).unwrap();
+            PublicDir(base)
+        }
+
+        fn root(&self) -> std::path::PathBuf {
+            self.0.join("public")
+        }
+    }
+
+    impl Drop for PublicDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn serve_status(dir: &PublicDir, path: &str) -> StatusCode {
+        match serve_file_under(&dir.root(), path).await {
+            Ok(_) => StatusCode::Ok,
+            Err(e) => e.status(),
+        }
+    }
+
+    #[async_std::test]
+    async fn serves_files_under_root() -> Result<()> {
+        let dir = PublicDir::new("serve-ok");
+        let body = serve_file_under(&dir.root(), "index.txt").await?;
+        assert_eq!(body.into_string().await?, "index");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn refuses_parent_dir() {
+        let dir = PublicDir::new("serve-parent");
+        assert_eq!(
+            serve_status(&dir, "../secret.txt").await,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            serve_status(&dir, "static/../../secret.txt").await,
+            StatusCode::NotFound
+        );
+    }
+
+    #[async_std::test]
+    async fn refuses_dotfiles() {
+        let dir = PublicDir::new("serve-dotfile");
+        assert_eq!(serve_status(&dir, ".env").await, StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn refuses_symlinks_out_of_root() {
+        let dir = PublicDir::new("serve-symlink");
+        assert_eq!(serve_status(&dir, "link.txt").await, StatusCode::NotFound);
+    }
+
+    #[async_std::test]
+    async fn refuses_directories() {
+        let dir = PublicDir::new("serve-dir");
+        assert_eq!(serve_status(&dir, "static").await, StatusCode::NotFound);
+        assert_eq!(serve_status(&dir, "").await, StatusCode::NotFound);
+    }
 }
@@ -0,0 +1,116 @@
+use crate::db;
+use crate::handlers::get_config_by_name;
+use crate::AppState;
+use async_std::future;
+use serde::Serialize;
+use sqlx::Connection;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tide::{Body, Response, Result, StatusCode};
+
+type Request = tide::Request<AppState>;
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, String>,
+}
+
+/// Liveness: answers as long as the process can serve requests at all.
+pub(crate) async fn get_healthz(_req: Request) -> Result<&'static str> {
+    Ok("ok")
+}
+
+/// Readiness: MySQL answers, `/initialize` has loaded the categories and, when
+/// `health.check_services` is set, the payment and shipment services respond.
+/// Replies 503 with the failing checks otherwise.
+pub(crate) async fn get_readyz(req: Request) -> Result<Response> {
+    let state = req.state();
+    let health = &state.config.health;
+    let timeout = Duration::from_millis(health.timeout_ms);
+    let mut checks = BTreeMap::new();
+
+    checks.insert("mysql", outcome(check_mysql(state), timeout).await);
+    checks.insert(
+        "categories",
+        outcome(check_categories(state), timeout).await,
+    );
+    if health.check_services {
+        let services = &state.config.services;
+        for &(name, key, default) in &[
+            (
+                "payment_service",
+                "payment_service_url",
+                &services.payment_url,
+            ),
+            (
+                "shipment_service",
+                "shipment_service_url",
+                &services.shipment_url,
+            ),
+        ] {
+            checks.insert(
+                name,
+                outcome(check_service(state, key, default), timeout).await,
+            );
+        }
+    }
+
+    let ready = checks.values().all(|outcome| outcome == "ok");
+    let mut res = Response::new(if ready {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    });
+    res.set_body(Body::from_json(&Readiness { ready, checks })?);
+    Ok(res)
+}
+
+async fn outcome(
+    check: impl std::future::Future<Output = Result<()>>,
+    timeout: Duration,
+) -> String {
+    match future::timeout(timeout, check).await {
+        Ok(Ok(())) => "ok".to_string(),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("timed out after {:?}", timeout),
+    }
+}
+
+async fn check_mysql(state: &AppState) -> Result<()> {
+    state.conn.acquire().await?.ping().await?;
+    Ok(())
+}
+
+async fn check_categories(state: &AppState) -> Result<()> {
+    let mut conn = state.conn.acquire().await?;
+    let (count,): (i64,) = db::timed("SELECT COUNT(*) FROM `categories`", |sql| {
+        sqlx::query_as(sql).fetch_one(&mut conn)
+    })
+    .await?;
+    if count == 0 {
+        return Err(tide::Error::from_str(
+            StatusCode::ServiceUnavailable,
+            "no categories loaded",
+        ));
+    }
+    Ok(())
+}
+
+/// Any answer below 500 counts; the services don't expose a dedicated health route.
+async fn check_service(state: &AppState, key: &str, default: &str) -> Result<()> {
+    let mut conn = state.conn.acquire().await?;
+    let url = get_config_by_name(&mut conn, key)
+        .await
+        .unwrap_or_else(|_| default.to_string());
+    drop(conn);
+
+    let res = surf::get(&url).await?;
+    if res.status().is_server_error() {
+        return Err(tide::Error::from_str(
+            StatusCode::ServiceUnavailable,
+            format!("{} answered {}", url, res.status()),
+        ));
+    }
+    Ok(())
+}
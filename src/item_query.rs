@@ -0,0 +1,192 @@
+use crate::db;
+use crate::models::Item;
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::mysql::{MySql, MySqlArguments};
+use sqlx::{Arguments, Executor};
+use std::convert::TryFrom;
+
+type Time = DateTime<Utc>;
+
+const ITEM_COLUMNS: &str = r"
+    `id`,
+    `seller_id`,
+    `buyer_id`,
+    `status`,
+    `name`,
+    `price`,
+    `description`,
+    `image_name`,
+    `category_id`,
+    `created_at`,
+    `updated_at`
+";
+
+/// Position of the last item on a page, ordered by `created_at DESC, id DESC`.
+#[derive(Clone, Copy)]
+pub(crate) struct Keyset {
+    pub(crate) item_id: u64,
+    pub(crate) created_at: Time,
+}
+
+impl Keyset {
+    /// Builds a keyset from the `item_id` and `created_at` (unix seconds) query params.
+    /// Returns `None` unless both are present and valid, which means "first page".
+    pub(crate) fn from_params(item_id: Option<u64>, created_at: Option<u64>) -> Option<Self> {
+        let item_id = item_id.filter(|&id| id > 0)?;
+        let created_at = created_at.filter(|&ts| ts > 0)?;
+        let created_at = Utc
+            .timestamp_opt(i64::try_from(created_at).ok()?, 0)
+            .single()?;
+        Some(Keyset {
+            item_id,
+            created_at,
+        })
+    }
+}
+
+enum Filter {
+    Id(u64),
+    StatusIn(&'static [&'static str]),
+    OwnedBy(u64),
+    RootCategory(u32),
+}
+
+/// Builder for `SELECT ... FROM items`, producing SQL with bind parameters only.
+///
+/// ```ignore
+/// let items = ItemQuery::new()
+///     .status_in(&[consts::ITEM_STATUS_ON_SALE, consts::ITEM_STATUS_SOLD_OUT])
+///     .page(keyset, consts::ITEMS_PER_PAGE + 1)
+///     .fetch_all(&mut conn)
+///     .await?;
+/// ```
+pub(crate) struct ItemQuery {
+    filters: Vec<Filter>,
+    page: Option<(Option<Keyset>, i32)>,
+}
+
+impl ItemQuery {
+    pub(crate) fn new() -> Self {
+        ItemQuery {
+            filters: Vec::new(),
+            page: None,
+        }
+    }
+
+    pub(crate) fn by_id(item_id: u64) -> Self {
+        let mut query = Self::new();
+        query.filters.push(Filter::Id(item_id));
+        query
+    }
+
+    pub(crate) fn status_in(mut self, statuses: &'static [&'static str]) -> Self {
+        self.filters.push(Filter::StatusIn(statuses));
+        self
+    }
+
+    /// Items the user is either selling or has bought.
+    pub(crate) fn owned_by(mut self, user_id: u64) -> Self {
+        self.filters.push(Filter::OwnedBy(user_id));
+        self
+    }
+
+    pub(crate) fn root_category(mut self, root_category_id: u32) -> Self {
+        self.filters.push(Filter::RootCategory(root_category_id));
+        self
+    }
+
+    /// Orders newest first and returns at most `limit` items older than `after`.
+    pub(crate) fn page(mut self, after: Option<Keyset>, limit: i32) -> Self {
+        self.page = Some((after, limit));
+        self
+    }
+
+    /// The `LIMIT` of a paged query.
+    #[cfg(test)]
+    pub(crate) fn limit(&self) -> Option<i32> {
+        self.page.map(|(_, limit)| limit)
+    }
+
+    pub(crate) fn to_sql(&self) -> String {
+        let mut conditions: Vec<String> = self
+            .filters
+            .iter()
+            .map(|filter| match filter {
+                Filter::Id(_) => "`id` = ?".to_string(),
+                Filter::StatusIn(statuses) => {
+                    format!("`status` IN ({})", vec!["?"; statuses.len()].join(","))
+                }
+                Filter::OwnedBy(_) => "(`seller_id` = ? OR `buyer_id` = ?)".to_string(),
+                Filter::RootCategory(_) => "`root_category_id` = ?".to_string(),
+            })
+            .collect();
+        if let Some((Some(_), _)) = self.page {
+            conditions.push("(`created_at` < ? OR (`created_at` <= ? AND `id` < ?))".to_string());
+        }
+
+        let mut sql = format!("SELECT {} FROM `items`", ITEM_COLUMNS);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        if self.page.is_some() {
+            sql.push_str(" ORDER BY `created_at` DESC, `id` DESC LIMIT ?");
+        }
+        sql
+    }
+
+    /// Bind values in the same order as the placeholders of [`to_sql`](Self::to_sql).
+    pub(crate) fn arguments(&self) -> MySqlArguments {
+        let mut args = MySqlArguments::default();
+        for filter in &self.filters {
+            match *filter {
+                Filter::Id(item_id) => args.add(item_id),
+                Filter::StatusIn(statuses) => {
+                    for &status in statuses {
+                        args.add(status);
+                    }
+                }
+                Filter::OwnedBy(user_id) => {
+                    args.add(user_id);
+                    args.add(user_id);
+                }
+                Filter::RootCategory(root_category_id) => args.add(root_category_id),
+            }
+        }
+        if let Some((after, limit)) = self.page {
+            if let Some(Keyset {
+                item_id,
+                created_at,
+            }) = after
+            {
+                args.add(created_at);
+                args.add(created_at);
+                args.add(item_id);
+            }
+            args.add(limit);
+        }
+        args
+    }
+
+    pub(crate) async fn fetch_all<'e, E>(&self, executor: E) -> sqlx::Result<Vec<Item>>
+    where
+        E: Executor<'e, Database = MySql>,
+    {
+        let sql = self.to_sql();
+        db::timed(&sql, |sql| {
+            sqlx::query_as_with(sql, self.arguments()).fetch_all(executor)
+        })
+        .await
+    }
+
+    pub(crate) async fn fetch_one<'e, E>(&self, executor: E) -> sqlx::Result<Item>
+    where
+        E: Executor<'e, Database = MySql>,
+    {
+        let sql = self.to_sql();
+        db::timed(&sql, |sql| {
+            sqlx::query_as_with(sql, self.arguments()).fetch_one(executor)
+        })
+        .await
+    }
+}
@@ -0,0 +1,150 @@
+use crate::tls::{Certificates, TlsListener};
+use async_std::io;
+use std::env;
+use std::fs;
+use std::net;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use tide::listener::ConcurrentListener;
+
+/// First file descriptor handed over by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// One entry of `server.listen`.
+///
+/// * `host:port` binds a TCP socket.
+/// * `tls:host:port` binds a TCP socket serving HTTPS with the `[tls]` certificate.
+/// * `unix:/path/to.sock` binds a Unix domain socket, e.g. for nginx.
+/// * `fd:N` serves on an already-bound socket inherited as descriptor `N`.
+/// * `systemd` serves on every socket passed via `LISTEN_FDS`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ListenAddr {
+    Tcp(String),
+    Tls(String),
+    Unix(PathBuf),
+    Fd(RawFd),
+    Systemd,
+}
+
+impl ListenAddr {
+    pub(crate) fn parse(addr: &str) -> Result<Self, String> {
+        if addr == "systemd" {
+            Ok(ListenAddr::Systemd)
+        } else if let Some(tcp) = addr.strip_prefix("tls:") {
+            if !tcp.contains(':') {
+                return Err(format!("expected `tls:host:port`, got `{}`", addr));
+            }
+            Ok(ListenAddr::Tls(tcp.to_string()))
+        } else if let Some(path) = addr.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("missing socket path in `{}`", addr));
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else if let Some(fd) = addr.strip_prefix("fd:") {
+            fd.parse()
+                .map(ListenAddr::Fd)
+                .map_err(|_| format!("invalid file descriptor in `{}`", addr))
+        } else if addr.contains(':') {
+            Ok(ListenAddr::Tcp(addr.to_string()))
+        } else {
+            Err(format!("unrecognized listen address `{}`", addr))
+        }
+    }
+
+    pub(crate) fn is_tls(&self) -> bool {
+        matches!(self, ListenAddr::Tls(_))
+    }
+}
+
+/// Builds a listener serving on every configured address at once. `certificates` must be
+/// given when any of them is a `tls:` address.
+pub(crate) fn bind<State>(
+    addrs: &[ListenAddr],
+    certificates: Option<&Arc<Certificates>>,
+) -> io::Result<ConcurrentListener<State>>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let mut listener = ConcurrentListener::new();
+    for addr in addrs {
+        match addr {
+            ListenAddr::Tcp(addr) => listener.add(addr.as_str())?,
+            ListenAddr::Tls(addr) => {
+                let certificates = certificates.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("no TLS certificate configured for tls:{}", addr),
+                    )
+                })?;
+                listener.add(TlsListener::new(addr, certificates))?
+            }
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                listener.add(UnixListener::bind(path)?)?;
+            }
+            ListenAddr::Fd(fd) => add_inherited(&mut listener, *fd)?,
+            ListenAddr::Systemd => {
+                for fd in systemd_fds()? {
+                    add_inherited(&mut listener, fd)?;
+                }
+            }
+        }
+    }
+    Ok(listener)
+}
+
+/// A socket file left behind by a previous run would make `bind` fail.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+fn add_inherited<State>(listener: &mut ConcurrentListener<State>, fd: RawFd) -> io::Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    // Only an AF_INET/AF_INET6 socket has an IP local address; anything else is
+    // treated as a Unix domain socket.
+    let tcp = unsafe { net::TcpListener::from_raw_fd(fd) };
+    if tcp.local_addr().is_ok() {
+        listener.add(tcp)
+    } else {
+        let fd = tcp.into_raw_fd();
+        listener.add(unsafe { UnixListener::from_raw_fd(fd) })
+    }
+}
+
+/// Descriptors passed by systemd socket activation (`sd_listen_fds(3)`).
+fn systemd_fds() -> io::Result<Vec<RawFd>> {
+    let not_activated = || {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "not started by systemd socket activation",
+        )
+    };
+
+    let pid: u32 = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(not_activated)?;
+    if pid != process::id() {
+        return Err(not_activated());
+    }
+    let count: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .ok_or_else(not_activated)?;
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect())
+}
@@ -0,0 +1,96 @@
+use crate::access_log::current_request_id;
+use crate::config::LogConfig;
+use chrono::{SecondsFormat, Utc};
+use log::{kv, Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Module path sqlx reports each executed statement under.
+const SQLX_QUERY_MODULE: &str = "sqlx::query";
+
+/// `log.slow_query_ms`, for [`log_slow_query`].
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Installs the process-wide logger: one JSON object per line on stdout. Records logged
+/// while a request is being handled carry its `request_id`.
+///
+/// sqlx's own per-statement records are dropped; statements are timed by
+/// [`crate::db::timed`] instead, which reports the slow ones through [`log_slow_query`].
+pub(crate) fn start(config: &LogConfig) -> Result<(), log::SetLoggerError> {
+    let level = config.level_filter();
+    SLOW_QUERY_MS.store(config.slow_query_ms, Ordering::Relaxed);
+    log::set_boxed_logger(Box::new(Logger { level }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Logs `sql` as a warning when it ran for at least `log.slow_query_ms`.
+pub(crate) fn log_slow_query(sql: &str, elapsed: Duration) {
+    let threshold = Duration::from_millis(SLOW_QUERY_MS.load(Ordering::Relaxed));
+    if elapsed < threshold || log::max_level() < Level::Warn {
+        return;
+    }
+    let mut fields = Map::new();
+    fields.insert("message".into(), "slow query".into());
+    fields.insert("elapsed_ms".into(), (elapsed.as_secs_f64() * 1000.0).into());
+    fields.insert("sql".into(), sql.trim().into());
+    if let Some(request_id) = current_request_id() {
+        fields.insert("request_id".into(), request_id.into());
+    }
+    write_line(Level::Warn, fields);
+}
+
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if record.module_path() == Some(SQLX_QUERY_MODULE) {
+            return;
+        }
+        if self.enabled(record.metadata()) {
+            let mut fields = Map::new();
+            fields.insert("message".into(), record.args().to_string().into());
+            fields.insert("target".into(), record.target().into());
+            if let Some(request_id) = current_request_id() {
+                fields.insert("request_id".into(), request_id.into());
+            }
+            let _ = record.key_values().visit(&mut KvVisitor(&mut fields));
+            write_line(record.level(), fields);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+struct KvVisitor<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> kv::Visitor<'kvs> for KvVisitor<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+fn write_line(level: Level, mut fields: Map<String, Value>) {
+    fields.insert(
+        "time".into(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    fields.insert("level".into(), level.to_string().to_lowercase().into());
+    if let Ok(mut json) = serde_json::to_vec(&fields) {
+        json.push(b'\n');
+        let _ = io::stdout().lock().write_all(&json);
+    }
+}
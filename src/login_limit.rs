@@ -0,0 +1,516 @@
+use crate::config::{LoginLimitBackend, LoginLimitConfig};
+use crate::db::{self, Db};
+use async_std::task;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{Done, Transaction};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tide::http::headers::RETRY_AFTER;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+type Time = DateTime<Utc>;
+
+/// Entries untouched for this long are back to a full bucket and no failures, so they
+/// can be forgotten.
+const IDLE_AFTER: Duration = Duration::from_secs(60 * 60);
+/// The in-memory backend tracks at most this many keys, forgetting the least recently
+/// used ones to make room.
+const MAX_MEMORY_ENTRIES: usize = 100_000;
+
+/// Token buckets per client IP and per `account_name`, plus a temporary lockout of
+/// accounts after repeated failed logins. Attached to `POST /login` in `main`.
+///
+/// Every attempt takes a token from both buckets; a 401 from the handler counts as a
+/// failure for the account, anything else successful resets its failures. Refused
+/// attempts get 429 with `Retry-After`.
+pub(crate) struct LoginLimit {
+    config: LoginLimitConfig,
+    backend: Backend,
+}
+
+enum Backend {
+    Memory(Mutex<MemoryEntries>),
+    Mysql(Db),
+}
+
+/// Entries kept in process, bounded by [`MAX_MEMORY_ENTRIES`].
+#[derive(Default)]
+struct MemoryEntries {
+    entries: HashMap<String, Entry>,
+    /// Every key by `updated_at`, oldest first.
+    by_age: BTreeSet<(Time, String)>,
+}
+
+impl MemoryEntries {
+    /// Runs `f` on the entry for `key`, creating it if needed. A new key at the limit
+    /// evicts the least recently used entry that isn't locked out, so flooding the
+    /// limiter with fresh keys can't lift a lockout.
+    fn update<T>(
+        &mut self,
+        key: &str,
+        rate: Rate,
+        now: Time,
+        f: impl FnOnce(&mut Entry) -> T,
+    ) -> T {
+        let mut entry = match self.entries.remove(key) {
+            Some(entry) => {
+                self.by_age.remove(&(entry.updated_at, key.to_string()));
+                entry
+            }
+            None => {
+                if self.entries.len() >= MAX_MEMORY_ENTRIES {
+                    self.evict(now);
+                }
+                Entry::new(rate, now)
+            }
+        };
+        let result = f(&mut entry);
+        self.by_age.insert((entry.updated_at, key.to_string()));
+        self.entries.insert(key.to_string(), entry);
+        result
+    }
+
+    fn evict(&mut self, now: Time) {
+        let entries = &self.entries;
+        let victim = self
+            .by_age
+            .iter()
+            .find(|(_, key)| entries[key].locked_until.is_none_or(|until| until <= now))
+            .or_else(|| self.by_age.iter().next())
+            .cloned();
+        if let Some((updated_at, key)) = victim {
+            self.by_age.remove(&(updated_at, key.clone()));
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// The state behind one key, identical for both backends.
+#[derive(Clone, Copy)]
+struct Entry {
+    tokens: f64,
+    updated_at: Time,
+    failures: u32,
+    locked_until: Option<Time>,
+}
+
+/// A token bucket's size and refill rate.
+#[derive(Clone, Copy)]
+struct Rate {
+    burst: u32,
+    per_minute: u32,
+}
+
+/// What happened to a login attempt, once the handler has answered.
+#[derive(Clone, Copy)]
+enum Outcome {
+    Failed,
+    Succeeded,
+}
+
+impl Entry {
+    fn new(rate: Rate, now: Time) -> Self {
+        Entry {
+            tokens: rate.burst.into(),
+            updated_at: now,
+            failures: 0,
+            locked_until: None,
+        }
+    }
+
+    /// Takes a token, or says how long until one is available or the lockout ends.
+    fn take(&mut self, rate: Rate, now: Time) -> Result<(), Duration> {
+        if let Some(remaining) = self
+            .locked_until
+            .filter(|&until| until > now)
+            .and_then(|until| (until - now).to_std().ok())
+        {
+            return Err(remaining);
+        }
+        let elapsed = (now - self.updated_at).to_std().unwrap_or_default();
+        let per_sec = f64::from(rate.per_minute) / 60.0;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * per_sec).min(rate.burst.into());
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if per_sec > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        } else {
+            Err(IDLE_AFTER)
+        }
+    }
+
+    fn record(&mut self, outcome: Outcome, config: &LoginLimitConfig, now: Time) {
+        match outcome {
+            Outcome::Succeeded => self.failures = 0,
+            Outcome::Failed => {
+                self.failures += 1;
+                if self.failures >= config.lockout_failures {
+                    self.failures = 0;
+                    self.locked_until = Some(now + chrono_duration(config.lockout_secs));
+                }
+            }
+        }
+    }
+}
+
+fn chrono_duration(secs: u64) -> chrono::Duration {
+    chrono::Duration::seconds(secs as i64)
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    account_name: String,
+}
+
+impl LoginLimit {
+    pub(crate) fn new(config: &LoginLimitConfig, db: &Db) -> Self {
+        let backend = match config.backend {
+            LoginLimitBackend::Memory => Backend::Memory(Mutex::default()),
+            LoginLimitBackend::Mysql => Backend::Mysql(db.clone()),
+        };
+        LoginLimit {
+            config: config.clone(),
+            backend,
+        }
+    }
+
+    fn ip_rate(&self) -> Rate {
+        Rate {
+            burst: self.config.ip_burst,
+            per_minute: self.config.ip_per_minute,
+        }
+    }
+
+    fn account_rate(&self) -> Rate {
+        Rate {
+            burst: self.config.account_burst,
+            per_minute: self.config.account_per_minute,
+        }
+    }
+
+    /// Takes a token for the IP and the account, or returns the longer of the waits.
+    async fn take(&self, ip: &str, account: Option<&str>) -> tide::Result<Result<(), Duration>> {
+        let mut keys = vec![(format!("ip:{}", ip), self.ip_rate())];
+        if let Some(account) = account {
+            keys.push((format!("account:{}", account), self.account_rate()));
+        }
+        let now = Utc::now();
+
+        let results = match &self.backend {
+            Backend::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+                keys.iter()
+                    .map(|(key, rate)| {
+                        entries.update(key, *rate, now, |entry| entry.take(*rate, now))
+                    })
+                    .collect::<Vec<_>>()
+            }
+            Backend::Mysql(db) => {
+                // Rows are created and locked in key order (see `load`), so concurrent
+                // attempts can't deadlock.
+                keys.sort_by(|a, b| a.0.cmp(&b.0));
+                let mut tx = db.begin().await?;
+                let mut results = Vec::new();
+                for (key, rate) in &keys {
+                    let mut entry = load(&mut tx, key, *rate, now).await?;
+                    results.push(entry.take(*rate, now));
+                    save(&mut tx, key, &entry).await?;
+                }
+                tx.commit().await?;
+                results
+            }
+        };
+
+        Ok(results
+            .into_iter()
+            .filter_map(Result::err)
+            .max()
+            .map_or(Ok(()), Err))
+    }
+
+    async fn record(&self, account: &str, outcome: Outcome) -> tide::Result<()> {
+        let key = format!("account:{}", account);
+        let now = Utc::now();
+        match &self.backend {
+            Backend::Memory(entries) => {
+                entries
+                    .lock()
+                    .unwrap()
+                    .update(&key, self.account_rate(), now, |entry| {
+                        entry.record(outcome, &self.config, now)
+                    });
+            }
+            Backend::Mysql(db) => {
+                let mut tx = db.begin().await?;
+                let mut entry = load(&mut tx, &key, self.account_rate(), now).await?;
+                entry.record(outcome, &self.config, now);
+                save(&mut tx, &key, &entry).await?;
+                tx.commit().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes idle rows from `login_limits` every [`IDLE_AFTER`], for the MySQL backend.
+    pub(crate) fn spawn_cleanup(&self) {
+        let db = match &self.backend {
+            Backend::Mysql(db) => db.clone(),
+            Backend::Memory(_) => return,
+        };
+        task::spawn(async move {
+            loop {
+                task::sleep(IDLE_AFTER).await;
+                let now = Utc::now();
+                let result = sqlx::query(
+                    "DELETE FROM `login_limits` WHERE `updated_at` < ? AND (`locked_until` IS NULL OR `locked_until` < ?)",
+                )
+                .bind(now - chrono_duration(IDLE_AFTER.as_secs()))
+                .bind(now)
+                .execute(db.pool())
+                .await;
+                match result {
+                    Ok(done) if done.rows_affected() > 0 => {
+                        tide::log::debug!("deleted idle login limits", { count: done.rows_affected() })
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tide::log::warn!("login limit cleanup failed", { error: e.to_string() })
+                    }
+                }
+            }
+        });
+    }
+
+    /// The client address, without the port. Forwarding headers are only believed when
+    /// `login_limit.trust_forwarded` says a proxy in front sets them.
+    fn client_ip<State>(&self, req: &Request<State>) -> String {
+        let addr = if self.config.trust_forwarded {
+            req.remote()
+        } else {
+            req.peer_addr()
+        };
+        let addr = addr.unwrap_or("unknown");
+        addr.parse::<SocketAddr>()
+            .map(|addr| addr.ip())
+            .or_else(|_| addr.parse::<IpAddr>())
+            .map_or_else(|_| addr.to_string(), |ip| ip.to_string())
+    }
+}
+
+/// Locks the row for `key`, creating it with a full bucket first if needed.
+///
+/// `SELECT ... FOR UPDATE` on a missing key only takes a gap lock, which doesn't stop
+/// a concurrent attempt from doing the same; both would then deadlock in [`save`]. The
+/// insert makes sure there is a row, and a lock on it, before reading.
+async fn load(
+    tx: &mut Transaction<'static, sqlx::MySql>,
+    key: &str,
+    rate: Rate,
+    now: Time,
+) -> sqlx::Result<Entry> {
+    let fresh = Entry::new(rate, now);
+    db::timed(
+        r"
+        INSERT INTO `login_limits` (`key`, `tokens`, `updated_at`, `failures`, `locked_until`)
+        VALUES (?, ?, ?, 0, NULL)
+        ON DUPLICATE KEY UPDATE `key` = `key`
+        ",
+        |sql| {
+            sqlx::query(sql)
+                .bind(key)
+                .bind(fresh.tokens)
+                .bind(fresh.updated_at)
+                .execute(&mut *tx)
+        },
+    )
+    .await?;
+    let (tokens, updated_at, failures, locked_until): (f64, Time, u32, Option<Time>) =
+        db::timed(
+            "SELECT `tokens`, `updated_at`, `failures`, `locked_until` FROM `login_limits` WHERE `key` = ? FOR UPDATE",
+            |sql| sqlx::query_as(sql).bind(key).fetch_one(&mut *tx),
+        )
+        .await?;
+    Ok(Entry {
+        tokens,
+        updated_at,
+        failures,
+        locked_until,
+    })
+}
+
+async fn save(
+    tx: &mut Transaction<'static, sqlx::MySql>,
+    key: &str,
+    entry: &Entry,
+) -> sqlx::Result<()> {
+    db::timed(
+        r"
+        INSERT INTO `login_limits` (`key`, `tokens`, `updated_at`, `failures`, `locked_until`)
+        VALUES (?, ?, ?, ?, ?)
+        ON DUPLICATE KEY UPDATE
+            `tokens` = VALUES(`tokens`),
+            `updated_at` = VALUES(`updated_at`),
+            `failures` = VALUES(`failures`),
+            `locked_until` = VALUES(`locked_until`)
+        ",
+        |sql| {
+            sqlx::query(sql)
+                .bind(key)
+                .bind(entry.tokens)
+                .bind(entry.updated_at)
+                .bind(entry.failures)
+                .bind(entry.locked_until)
+                .execute(&mut *tx)
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for LoginLimit {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // The handler reads the body itself, so it's put back once the account is known.
+        let body = req.body_bytes().await?;
+        let account = serde_json::from_slice::<LoginRequest>(&body)
+            .ok()
+            .map(|login| login.account_name);
+        req.set_body(body);
+
+        let ip = self.client_ip(&req);
+        if let Err(retry_after) = self.take(&ip, account.as_deref()).await? {
+            tide::log::warn!("login rate limited", {
+                ip: ip,
+                account_name: account.unwrap_or_default(),
+            });
+            let mut res = Response::new(StatusCode::TooManyRequests);
+            // Rounded up, so retrying right on time succeeds.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.insert_header(RETRY_AFTER, secs.to_string());
+            res.set_body("too many login attempts");
+            return Ok(res);
+        }
+
+        let res = next.run(req).await;
+        if let Some(account) = account {
+            if res.status() == StatusCode::Unauthorized {
+                self.record(&account, Outcome::Failed).await?;
+            } else if res.status().is_success() {
+                self.record(&account, Outcome::Succeeded).await?;
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const RATE: Rate = Rate {
+        burst: 2,
+        per_minute: 60,
+    };
+
+    fn at(secs: f64) -> Time {
+        Utc.timestamp_opt(1_565_000_000, 0).unwrap()
+            + chrono::Duration::milliseconds((secs * 1000.0) as i64)
+    }
+
+    fn config() -> LoginLimitConfig {
+        LoginLimitConfig {
+            lockout_failures: 3,
+            lockout_secs: 300,
+            ..LoginLimitConfig::default()
+        }
+    }
+
+    #[test]
+    fn refills_at_rate() {
+        let mut entry = Entry::new(RATE, at(0.0));
+        assert!(entry.take(RATE, at(0.0)).is_ok());
+        assert!(entry.take(RATE, at(0.0)).is_ok());
+        assert_eq!(entry.take(RATE, at(0.0)), Err(Duration::from_secs(1)));
+        assert_eq!(entry.take(RATE, at(0.5)), Err(Duration::from_millis(500)));
+        assert!(entry.take(RATE, at(1.0)).is_ok());
+        // Refilling stops at the burst.
+        assert!(entry.take(RATE, at(60.0)).is_ok());
+        assert!(entry.take(RATE, at(60.0)).is_ok());
+        assert!(entry.take(RATE, at(60.0)).is_err());
+    }
+
+    #[test]
+    fn locks_out_after_consecutive_failures() {
+        let config = config();
+        let mut entry = Entry::new(RATE, at(0.0));
+        entry.record(Outcome::Failed, &config, at(0.0));
+        entry.record(Outcome::Failed, &config, at(0.0));
+        entry.record(Outcome::Succeeded, &config, at(0.0));
+        entry.record(Outcome::Failed, &config, at(0.0));
+        entry.record(Outcome::Failed, &config, at(0.0));
+        assert_eq!(entry.locked_until, None);
+
+        entry.record(Outcome::Failed, &config, at(10.0));
+        assert_eq!(entry.locked_until, Some(at(310.0)));
+        assert_eq!(entry.take(RATE, at(110.0)), Err(Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let config = config();
+        let mut entry = Entry::new(RATE, at(0.0));
+        for _ in 0..config.lockout_failures {
+            entry.record(Outcome::Failed, &config, at(0.0));
+        }
+        assert!(entry.take(RATE, at(299.0)).is_err());
+        assert!(entry.take(RATE, at(300.0)).is_ok());
+        assert_eq!(entry.failures, 0);
+    }
+
+    fn entries(keys: &[(&str, f64, Option<f64>)]) -> MemoryEntries {
+        let mut entries = MemoryEntries::default();
+        for &(key, updated_at, locked_until) in keys {
+            entries.update(key, RATE, at(updated_at), |entry| {
+                entry.locked_until = locked_until.map(at)
+            });
+        }
+        entries
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut entries = entries(&[("a", 0.0, None), ("b", 1.0, None), ("c", 2.0, None)]);
+        entries.update("a", RATE, at(3.0), |entry| {
+            entry.take(RATE, at(3.0)).unwrap()
+        });
+        entries.evict(at(3.0));
+        assert!(!entries.entries.contains_key("b"));
+        assert_eq!(entries.entries.len(), 2);
+        assert_eq!(entries.by_age.len(), 2);
+    }
+
+    #[test]
+    fn never_evicts_locked_while_unlocked_remain() {
+        let mut entries = entries(&[
+            ("locked", 0.0, Some(600.0)),
+            ("expired", 1.0, Some(5.0)),
+            ("idle", 2.0, None),
+        ]);
+        entries.evict(at(10.0));
+        assert!(!entries.entries.contains_key("expired"));
+        entries.evict(at(10.0));
+        assert!(!entries.entries.contains_key("idle"));
+        assert!(entries.entries.contains_key("locked"));
+
+        // With only locked entries left, the oldest goes.
+        entries.evict(at(10.0));
+        assert!(entries.entries.is_empty());
+        assert!(entries.by_age.is_empty());
+    }
+}
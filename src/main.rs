@@ -1,92 +1,258 @@
-use std::env;
-use tide::sessions::{MemoryStore, SessionMiddleware};
-use tide::{Result, StatusCode};
+use async_std::prelude::*;
+use config::{Command, Config, SessionStoreKind, TraceExporter};
+use csrf::CsrfProtection;
+use db::Db;
+use listener::ListenAddr;
+use login_limit::LoginLimit;
+use pagination::{CursorKeys, Paging};
+use secrets::Secrets;
+use security_headers::SecurityHeaders;
+use session::{SessionKeys, SessionMiddleware};
+use session_store::{EncryptedCookieStore, MySqlStore};
+use shutdown::InFlight;
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use std::sync::Arc;
+use std::time::Duration;
+use tide::sessions::MemoryStore;
+use tide::Result;
 
+mod access_log;
+mod config;
 mod consts;
+mod csrf;
+mod db;
 mod handlers;
+mod health;
+mod item_query;
+mod listener;
+mod logging;
+mod login_limit;
+mod metrics;
 mod models;
+mod pagination;
+mod profiling;
+mod secrets;
+mod security_headers;
+mod session;
+mod session_store;
+mod shutdown;
+mod tls;
+mod trace;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./sql/migrations");
 
 #[async_std::main]
 async fn main() -> Result<()> {
-    tide::log::start();
-
-    let host = env::var("MYSQL_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port = env::var("MYSQL_PORT").unwrap_or_else(|_| "3306".to_string());
-    port.parse::<i32>().map_err(|_| {
-        tide::Error::from_str(
-            StatusCode::InternalServerError,
-            "failed to read DB port number from an environment variable MYSQL_PORT.",
-        )
-    })?;
-    let user = env::var("MYSQL_USER").unwrap_or_else(|_| "isucari".to_string());
-    let dbname = env::var("MYSQL_DBNAME").unwrap_or_else(|_| "isucari".to_string());
-    let password = env::var("MYSQL_PASS").unwrap_or_else(|_| "isucari".to_string());
-
-    let dsn = format!(
-        "mysql://{}:{}@{}:{}/{}?charset=utf8mb4&parseTime=true&loc=Local",
-        user, password, host, port, dbname
-    );
-    let conn = connect(&dsn).await?;
-    let state = AppState { conn };
+    let config = match Config::load()? {
+        Command::Serve(config) => config,
+        Command::PrintConfig(config) => {
+            print!("{}", config.to_toml()?);
+            return Ok(());
+        }
+    };
+
+    logging::start(&config.log)?;
+    trace::start(&config.trace);
+
+    let secrets = Secrets::load(&config.secrets)?;
+    tide::log::info!("loaded secrets", { source: config.secrets.source.as_str() });
+    let conn = Db::connect(&config.database).await?;
+    run_migrations(conn.pool()).await?;
+    let session_keys = SessionKeys::new(&config.session.secret, &config.session.previous_secrets);
+    let paging = Paging {
+        items_per_page: config.paging.items_per_page,
+        transactions_per_page: config.paging.transactions_per_page,
+        cursor_keys: CursorKeys::new(&session_keys),
+    };
+    let listen_addrs = config.listen_addrs()?;
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let config = Arc::new(config);
+    let state = AppState {
+        conn: conn.clone(),
+        paging,
+        secrets: Arc::new(secrets),
+        config: config.clone(),
+    };
 
+    let in_flight = InFlight::default();
     let mut app = tide::with_state(state);
-    app.with(SessionMiddleware::new(
-        MemoryStore::new(),
-        consts::SESSION_SECRET.as_bytes(),
-    ));
+    app.with(metrics::HttpMetrics);
+    app.with(in_flight.clone());
+    if config.security_headers.enabled {
+        app.with(SecurityHeaders::new(&config.security_headers));
+    }
+    match config.session.store {
+        SessionStoreKind::Memory => {
+            app.with(SessionMiddleware::new(
+                MemoryStore::new(),
+                session_keys,
+                &config.session,
+            ));
+        }
+        SessionStoreKind::Mysql => {
+            let store = MySqlStore::new(conn.clone());
+            store.spawn_cleanup(Duration::from_secs(config.session.cleanup_interval_secs));
+            app.with(SessionMiddleware::new(store, session_keys, &config.session));
+        }
+        SessionStoreKind::Cookie => {
+            let store = EncryptedCookieStore::new(&session_keys);
+            app.with(SessionMiddleware::new(store, session_keys, &config.session));
+        }
+    }
+    app.with(access_log::AccessLog {
+        server_timing: config.log.server_timing,
+    });
+    if config.trace.exporter != TraceExporter::None {
+        app.with(trace::Tracing);
+    }
+    if config.debug.enabled {
+        tide::log::warn!("debug endpoints enabled under /debug/pprof");
+        app.with(profiling::AllocationCounter::new());
+    }
+
+    // Probes
+    route(&mut app, "/healthz").get(health::get_healthz);
+    route(&mut app, "/readyz").get(health::get_readyz);
+    route(&mut app, "/metrics").get(metrics::get_metrics);
+    if config.debug.enabled {
+        route(&mut app, "/debug/pprof/profile").get(profiling::get_profile);
+        route(&mut app, "/debug/pprof/allocs").get(profiling::get_allocs);
+    }
 
     // API
-    app.at("/initialize").post(handlers::post_initialize);
-    app.at("/new_items.json").get(handlers::get_new_items);
-    app.at("/new_items/:root_category_id.json")
-        .get(handlers::get_new_category_items);
-    app.at("users/transactions.json")
-        .get(handlers::get_transactions);
-    app.at("/items/:item_id.json").get(handlers::get_item);
-    app.at("/items/edit").post(handlers::post_item_edit);
-    app.at("/buy").post(handlers::post_buy);
-    app.at("/sell").post(handlers::post_sell);
-    app.at("/ship").post(handlers::post_ship);
-    app.at("/ship_done").post(handlers::post_ship_done);
-    app.at("/complete").post(handlers::post_complete);
-    app.at("/transactions/:transaction_evidence_id.png")
-        .get(handlers::get_qr_code);
-    app.at("/bump").post(handlers::post_bump);
-    app.at("/settings").get(handlers::get_settings);
-    app.at("/login").post(handlers::post_login);
-    app.at("/register").post(handlers::post_register);
-    app.at("/reports.json").get(handlers::get_reports);
+    route(&mut app, "/initialize").post(handlers::post_initialize);
+    route(&mut app, "/new_items.json").get(handlers::get_new_items);
+    route(&mut app, "/new_items/:root_category_id.json").get(handlers::get_new_category_items);
+    route(&mut app, "users/transactions.json").get(handlers::get_transactions);
+    route(&mut app, "/items/:item_id.json").get(handlers::get_item);
+    route(&mut app, "/items/edit")
+        .with(CsrfProtection)
+        .post(handlers::post_item_edit);
+    route(&mut app, "/buy")
+        .with(CsrfProtection)
+        .post(handlers::post_buy);
+    route(&mut app, "/sell")
+        .with(CsrfProtection)
+        .post(handlers::post_sell);
+    route(&mut app, "/ship")
+        .with(CsrfProtection)
+        .post(handlers::post_ship);
+    route(&mut app, "/ship_done")
+        .with(CsrfProtection)
+        .post(handlers::post_ship_done);
+    route(&mut app, "/complete")
+        .with(CsrfProtection)
+        .post(handlers::post_complete);
+    route(&mut app, "/transactions/:transaction_evidence_id.png").get(handlers::get_qr_code);
+    route(&mut app, "/bump")
+        .with(CsrfProtection)
+        .post(handlers::post_bump);
+    route(&mut app, "/settings").get(handlers::get_settings);
+    if config.login_limit.enabled {
+        let limit = LoginLimit::new(&config.login_limit, &conn);
+        limit.spawn_cleanup();
+        route(&mut app, "/login")
+            .with(limit)
+            .post(handlers::post_login);
+    } else {
+        route(&mut app, "/login").post(handlers::post_login);
+    }
+    route(&mut app, "/register").post(handlers::post_register);
+    route(&mut app, "/logout")
+        .with(CsrfProtection)
+        .post(handlers::post_logout);
+    route(&mut app, "/sessions/revoke_all")
+        .with(CsrfProtection)
+        .post(handlers::post_revoke_all_sessions);
+    route(&mut app, "/reports.json").get(handlers::get_reports);
+    route(&mut app, "/diagnostics.json").get(handlers::get_diagnostics);
 
     // Frontend
-    app.at("/").get(handlers::get_index);
-    app.at("/login").get(handlers::get_index);
-    app.at("/register").get(handlers::get_index);
-    app.at("/timeline").get(handlers::get_index);
-    app.at("/categories/:category_id/items")
-        .get(handlers::get_index);
-    app.at("/sell").get(handlers::get_index);
-    app.at("/items/:item_id").get(handlers::get_index);
-    app.at("/items/:item_id/edit").get(handlers::get_index);
-    app.at("/items/:item_id/buy").get(handlers::get_index);
-    app.at("/buy/complete").get(handlers::get_index);
-    app.at("/transactions/:transaction_id")
-        .get(handlers::get_index);
-    app.at("/users/:user_id").get(handlers::get_index);
-    app.at("/users/setting").get(handlers::get_index);
+    route(&mut app, "/").get(handlers::get_index);
+    route(&mut app, "/login").get(handlers::get_index);
+    route(&mut app, "/register").get(handlers::get_index);
+    route(&mut app, "/timeline").get(handlers::get_index);
+    route(&mut app, "/categories/:category_id/items").get(handlers::get_index);
+    route(&mut app, "/sell").get(handlers::get_index);
+    route(&mut app, "/items/:item_id").get(handlers::get_index);
+    route(&mut app, "/items/:item_id/edit").get(handlers::get_index);
+    route(&mut app, "/items/:item_id/buy").get(handlers::get_index);
+    route(&mut app, "/buy/complete").get(handlers::get_index);
+    route(&mut app, "/transactions/:transaction_id").get(handlers::get_index);
+    route(&mut app, "/users/:user_id").get(handlers::get_index);
+    route(&mut app, "/users/setting").get(handlers::get_index);
 
     // Assets
-    app.at("/*path").get(handlers::get_assets);
+    route(&mut app, "/upload/*path").get(handlers::get_upload);
+    route(&mut app, "/*path").get(handlers::get_assets);
 
-    app.listen("127.0.0.1:8080").await?;
+    // Dropping the listen future closes the listening sockets; connections already
+    // accepted keep running on their own tasks until `drain` lets them finish.
+    let certificates = if listen_addrs.iter().any(ListenAddr::is_tls) {
+        let certificates = tls::Certificates::load(&config.tls)?;
+        certificates.spawn_reload_on_sighup()?;
+        Some(certificates)
+    } else {
+        None
+    };
+    let listener = listener::bind(&listen_addrs, certificates.as_ref())?;
+    let signal = shutdown::signal()?;
+    let serve = async { app.listen(listener).await.map(|()| None) };
+    if let Some(signal) = serve.race(async { Ok(Some(signal.await)) }).await? {
+        tide::log::info!("received signal {}, shutting down", signal);
+        let remaining = in_flight.drain(shutdown_timeout).await;
+        if remaining > 0 {
+            tide::log::warn!(
+                "{} requests still in flight after {:?}",
+                remaining,
+                shutdown_timeout
+            );
+        }
+        trace::flush().await;
+        conn.close().await;
+    }
     Ok(())
 }
 
-async fn connect(url: &str) -> Result<sqlx::MySqlPool> {
-    let pool = sqlx::Pool::connect(url).await?;
-    Ok(pool)
+/// Registers `path`, labelling its requests with the pattern for `/metrics`.
+fn route<'a>(app: &'a mut tide::Server<AppState>, path: &'static str) -> tide::Route<'a, AppState> {
+    let mut route = app.at(path);
+    route.with(metrics::RoutePattern(path));
+    route
+}
+
+/// Applies pending migrations under `sql/migrations`.
+///
+/// This mirrors `Migrator::run`, which can't be awaited from a handler because of its
+/// higher-ranked `Acquire` bounds.
+pub(crate) async fn run_migrations(
+    pool: &sqlx::MySqlPool,
+) -> std::result::Result<(), MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.lock().await?;
+    conn.ensure_migrations_table().await?;
+
+    let (version, dirty) = conn.version().await?.unwrap_or((0, false));
+    if dirty {
+        return Err(MigrateError::Dirty(version));
+    }
+
+    for migration in MIGRATOR.iter() {
+        if migration.version > version {
+            conn.apply(migration).await?;
+        } else {
+            conn.validate(migration).await?;
+        }
+    }
+
+    conn.unlock().await?;
+    Ok(())
 }
 
 #[derive(Clone)]
 struct AppState {
-    conn: sqlx::MySqlPool,
+    conn: Db,
+    paging: Paging,
+    secrets: Arc<Secrets>,
+    config: Arc<Config>,
 }
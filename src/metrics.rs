@@ -0,0 +1,254 @@
+use crate::access_log;
+use crate::db::PoolStats;
+use crate::AppState;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tide::http::mime::Mime;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Label for requests that no route claimed, so arbitrary paths can't blow up cardinality.
+const UNMATCHED: &str = "unmatched";
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::default);
+
+#[derive(Default)]
+struct Registry {
+    /// Keyed by (method, route pattern).
+    http: Mutex<BTreeMap<(String, &'static str), Series>>,
+    /// Keyed by (service, operation).
+    outbound: Mutex<BTreeMap<(&'static str, &'static str), Series>>,
+}
+
+/// Counts per outcome label plus one latency histogram.
+#[derive(Default)]
+struct Series {
+    outcomes: BTreeMap<String, u64>,
+    latency: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, &le) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (count, le) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+impl Series {
+    fn observe(&mut self, outcome: String, elapsed: Duration) {
+        *self.outcomes.entry(outcome).or_insert(0) += 1;
+        self.latency.observe(elapsed);
+    }
+}
+
+/// Names the route pattern a request matched; attached per route in `main`.
+#[derive(Clone, Copy)]
+pub(crate) struct RoutePattern(pub(crate) &'static str);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RoutePattern {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        res.insert_ext(*self);
+        Ok(res)
+    }
+}
+
+/// Records count, status and latency of every request under its [`RoutePattern`].
+pub(crate) struct HttpMetrics;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HttpMetrics {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let res = next.run(req).await;
+        let route = res.ext::<RoutePattern>().map_or(UNMATCHED, |route| route.0);
+
+        REGISTRY
+            .http
+            .lock()
+            .unwrap()
+            .entry((method, route))
+            .or_default()
+            .observe(u16::from(res.status()).to_string(), started.elapsed());
+        Ok(res)
+    }
+}
+
+/// Times a call to the payment or shipment service and records whether it succeeded.
+pub(crate) async fn outbound<T, E>(
+    service: &'static str,
+    operation: &'static str,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let started = Instant::now();
+    let result = call.await;
+    let elapsed = started.elapsed();
+    let outcome = if result.is_ok() { "success" } else { "error" };
+
+    access_log::record_external_time(elapsed);
+    REGISTRY
+        .outbound
+        .lock()
+        .unwrap()
+        .entry((service, operation))
+        .or_default()
+        .observe(outcome.to_string(), elapsed);
+    result
+}
+
+/// Serves everything recorded so far in the Prometheus text format.
+pub(crate) async fn get_metrics(req: tide::Request<AppState>) -> tide::Result<Response> {
+    let mut out = String::new();
+    render_http(&mut out);
+    render_outbound(&mut out);
+    render_pool(&mut out, &req.state().conn.stats());
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(out);
+    res.set_content_type("text/plain; version=0.0.4".parse::<Mime>()?);
+    Ok(res)
+}
+
+fn render_http(out: &mut String) {
+    let http = REGISTRY.http.lock().unwrap();
+
+    out.push_str("# HELP isucari_http_requests_total HTTP requests by route pattern and status.\n");
+    out.push_str("# TYPE isucari_http_requests_total counter\n");
+    for ((method, route), series) in http.iter() {
+        for (status, count) in &series.outcomes {
+            let _ = writeln!(
+                out,
+                "isucari_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method,
+                escape(route),
+                status,
+                count
+            );
+        }
+    }
+
+    out.push_str(
+        "# HELP isucari_http_request_duration_seconds HTTP request latency by route pattern.\n",
+    );
+    out.push_str("# TYPE isucari_http_request_duration_seconds histogram\n");
+    for ((method, route), series) in http.iter() {
+        let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+        series
+            .latency
+            .render(out, "isucari_http_request_duration_seconds", &labels);
+    }
+}
+
+fn render_outbound(out: &mut String) {
+    let outbound = REGISTRY.outbound.lock().unwrap();
+
+    out.push_str(
+        "# HELP isucari_outbound_requests_total Calls to the payment and shipment services.\n",
+    );
+    out.push_str("# TYPE isucari_outbound_requests_total counter\n");
+    for ((service, operation), series) in outbound.iter() {
+        for (result, count) in &series.outcomes {
+            let _ = writeln!(
+                out,
+                "isucari_outbound_requests_total{{service=\"{}\",operation=\"{}\",result=\"{}\"}} {}",
+                service, operation, result, count
+            );
+        }
+    }
+
+    out.push_str("# HELP isucari_outbound_request_duration_seconds Latency of calls to the payment and shipment services.\n");
+    out.push_str("# TYPE isucari_outbound_request_duration_seconds histogram\n");
+    for ((service, operation), series) in outbound.iter() {
+        let labels = format!("service=\"{}\",operation=\"{}\"", service, operation);
+        series
+            .latency
+            .render(out, "isucari_outbound_request_duration_seconds", &labels);
+    }
+}
+
+fn render_pool(out: &mut String, stats: &PoolStats) {
+    let gauges: [(&str, &str, u64); 5] = [
+        (
+            "isucari_db_pool_size",
+            "Open MySQL connections.",
+            stats.size.into(),
+        ),
+        (
+            "isucari_db_pool_idle",
+            "Idle MySQL connections.",
+            stats.idle as u64,
+        ),
+        (
+            "isucari_db_pool_in_use",
+            "MySQL connections checked out by handlers.",
+            stats.in_use.into(),
+        ),
+        (
+            "isucari_db_pool_waiters",
+            "Handlers waiting for a MySQL connection.",
+            stats.waiters as u64,
+        ),
+        (
+            "isucari_db_pool_max_connections",
+            "Configured upper bound of the pool.",
+            stats.max_connections.into(),
+        ),
+    ];
+    for (name, help, value) in gauges.iter() {
+        let _ = writeln!(
+            out,
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}",
+            name, help, name, name, value
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP isucari_db_pool_acquire_timeouts_total Acquires that gave up waiting for a connection.\n\
+         # TYPE isucari_db_pool_acquire_timeouts_total counter\n\
+         isucari_db_pool_acquire_timeouts_total {}",
+        stats.acquire_timeouts
+    );
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
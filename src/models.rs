@@ -31,6 +31,8 @@ pub(crate) struct APIShipmentStatusRes {
 #[derive(Serialize)]
 pub(crate) struct ResTransactions {
     pub(crate) has_next: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_cursor: Option<String>,
     pub(crate) items: Vec<ItemDetail>,
 }
 
@@ -97,6 +99,9 @@ pub(crate) struct User {
     pub(crate) num_sell_items: i32,
     #[serde(skip)]
     pub(crate) last_bump: Time,
+    /// Bumped by `/sessions/revoke_all`; sessions carrying an older one are refused.
+    #[serde(skip)]
+    pub(crate) session_generation: u32,
     #[serde(skip)]
     pub(crate) created_at: Time,
 }
@@ -109,6 +114,7 @@ impl<'c> FromRow<'c, MySqlRow> for User {
         let address: Option<String> = row.try_get("address")?;
         let num_sell_items: i32 = row.try_get("num_sell_items")?;
         let last_bump: Time = row.try_get("last_bump")?;
+        let session_generation: u32 = row.try_get("session_generation")?;
         let created_at: Time = row.try_get("created_at")?;
         Ok(User {
             id,
@@ -117,6 +123,7 @@ impl<'c> FromRow<'c, MySqlRow> for User {
             address,
             num_sell_items,
             last_bump,
+            session_generation,
             created_at,
         })
     }
@@ -275,6 +282,26 @@ impl<'c> FromRow<'c, MySqlRow> for Shipping {
     }
 }
 
+#[derive(Serialize)]
+pub(crate) struct ShippingSimple {
+    pub(crate) transaction_evidence_id: u64,
+    pub(crate) status: String,
+    pub(crate) reserve_id: String,
+}
+
+impl<'c> FromRow<'c, MySqlRow> for ShippingSimple {
+    fn from_row(row: &MySqlRow) -> Result<Self, sqlx::Error> {
+        let transaction_evidence_id: u64 = row.try_get("transaction_evidence_id")?;
+        let status: String = row.try_get("status")?;
+        let reserve_id: String = row.try_get("reserve_id")?;
+        Ok(ShippingSimple {
+            transaction_evidence_id,
+            status,
+            reserve_id,
+        })
+    }
+}
+
 #[derive(Serialize)]
 pub(crate) struct Category {
     pub(crate) id: u32,
@@ -305,5 +332,7 @@ pub(crate) struct ResNewItems {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) root_category_name: Option<String>,
     pub(crate) has_next: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_cursor: Option<String>,
     pub(crate) items: Vec<ItemSimple>,
 }
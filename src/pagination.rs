@@ -0,0 +1,210 @@
+use crate::item_query::Keyset;
+use crate::models::Item;
+use crate::session::SessionKeys;
+use chrono::{DateTime, TimeZone, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type Time = DateTime<Utc>;
+
+const CURSOR_DOMAIN: &[u8] = b"isucari-cursor:";
+const CURSOR_KEY_DOMAIN: &[u8] = b"isucari-cursor-key:";
+
+/// Page sizes and the keys used to sign cursors, shared by all list endpoints.
+#[derive(Clone)]
+pub(crate) struct Paging {
+    pub(crate) items_per_page: i32,
+    pub(crate) transactions_per_page: i32,
+    pub(crate) cursor_keys: CursorKeys,
+}
+
+/// One cursor key per session secret, current first. Cursors are signed with the
+/// current key and accepted under any of them, so rotating the secret doesn't break
+/// the `next_cursor` a client is holding.
+#[derive(Clone)]
+pub(crate) struct CursorKeys {
+    keys: Vec<Vec<u8>>,
+}
+
+impl CursorKeys {
+    pub(crate) fn new(session_keys: &SessionKeys) -> Self {
+        CursorKeys {
+            keys: session_keys.derive(CURSOR_KEY_DOMAIN),
+        }
+    }
+
+    fn encode(&self, cursor: &Cursor) -> String {
+        cursor.encode(&self.keys[0])
+    }
+
+    fn decode(&self, token: &str) -> Option<Cursor> {
+        self.keys.iter().find_map(|key| Cursor::decode(token, key))
+    }
+}
+
+impl Paging {
+    /// Drops the look-ahead row fetched beyond `per_page` and returns `has_next` together
+    /// with the cursor for the following page.
+    pub(crate) fn finish_page(
+        &self,
+        items: &mut Vec<Item>,
+        per_page: i32,
+    ) -> (bool, Option<String>) {
+        let per_page = per_page as usize;
+        if items.len() <= per_page {
+            return (false, None);
+        }
+        items.truncate(per_page);
+        let next_cursor = items.last().map(|item| {
+            self.cursor_keys
+                .encode(&Cursor::after(item.id, item.created_at))
+        });
+        (true, next_cursor)
+    }
+}
+
+/// Opaque, tamper-evident token pointing just past the last item of a page.
+///
+/// The token is `base64url(created_at:item_id).base64url(hmac)`, so clients can
+/// only hand back cursors the server gave them.
+pub(crate) struct Cursor {
+    keyset: Keyset,
+}
+
+impl Cursor {
+    pub(crate) fn after(item_id: u64, created_at: Time) -> Self {
+        Cursor {
+            keyset: Keyset {
+                item_id,
+                created_at,
+            },
+        }
+    }
+
+    pub(crate) fn keyset(&self) -> Keyset {
+        self.keyset
+    }
+
+    pub(crate) fn encode(&self, key: &[u8]) -> String {
+        let payload = format!(
+            "{}:{}",
+            self.keyset.created_at.timestamp(),
+            self.keyset.item_id
+        );
+        let signature = sign(key, payload.as_bytes()).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub(crate) fn decode(token: &str, key: &[u8]) -> Option<Self> {
+        let mut parts = token.splitn(2, '.');
+        let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        sign(key, &payload).verify(&signature).ok()?;
+
+        let payload = String::from_utf8(payload).ok()?;
+        let mut fields = payload.splitn(2, ':');
+        let created_at: i64 = fields.next()?.parse().ok()?;
+        let item_id: u64 = fields.next()?.parse().ok()?;
+        let created_at = Utc.timestamp_opt(created_at, 0).single()?;
+        Some(Cursor::after(item_id, created_at))
+    }
+}
+
+fn sign(key: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(CURSOR_DOMAIN);
+    mac.update(payload);
+    mac
+}
+
+/// Query string accepted by the list endpoints.
+///
+/// `cursor` is what we hand out as `next_cursor`; the raw `item_id` and `created_at`
+/// pair is still accepted for clients that predate it.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct PageQuery {
+    cursor: Option<String>,
+    item_id: Option<u64>,
+    created_at: Option<u64>,
+}
+
+impl PageQuery {
+    /// Where the requested page starts, or `None` for the first page.
+    pub(crate) fn keyset(&self, keys: &CursorKeys) -> tide::Result<Option<Keyset>> {
+        match &self.cursor {
+            Some(token) => keys
+                .decode(token)
+                .map(|cursor| Some(cursor.keyset()))
+                .ok_or_else(|| {
+                    tide::Error::from_str(tide::StatusCode::BadRequest, "invalid cursor")
+                }),
+            None => Ok(Keyset::from_params(self.item_id, self.created_at)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn token() -> String {
+        Cursor::after(42, Utc.timestamp_opt(1_565_000_000, 0).unwrap()).encode(KEY)
+    }
+
+    #[test]
+    fn round_trip() {
+        let keyset = Cursor::decode(&token(), KEY).unwrap().keyset();
+        assert_eq!(keyset.item_id, 42);
+        assert_eq!(keyset.created_at.timestamp(), 1_565_000_000);
+    }
+
+    #[test]
+    fn rejects_modified_payload() {
+        let token = token();
+        let (payload, signature) = token.split_once('.').unwrap();
+        let mut payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap();
+        *payload.last_mut().unwrap() ^= 1;
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            signature
+        );
+        assert!(Cursor::decode(&forged, KEY).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_signature() {
+        let token = token();
+        assert!(Cursor::decode(&token[..token.len() - 4], KEY).is_none());
+        let payload = token.split_once('.').unwrap().0;
+        assert!(Cursor::decode(payload, KEY).is_none());
+        assert!(Cursor::decode(&format!("{}.", payload), KEY).is_none());
+    }
+
+    #[test]
+    fn rejects_other_key() {
+        assert!(Cursor::decode(&token(), b"another key entirely").is_none());
+    }
+
+    #[test]
+    fn accepts_cursors_signed_before_rotation() {
+        let old = "an old secret of at least thirty-two bytes";
+        let new = "a new secret of at least thirty-two bytes!";
+        let cursor = Cursor::after(42, Utc.timestamp_opt(1_565_000_000, 0).unwrap());
+        let token = CursorKeys::new(&SessionKeys::new(old, &[])).encode(&cursor);
+
+        let rotated = CursorKeys::new(&SessionKeys::new(new, &[old.to_string()]));
+        assert_eq!(rotated.decode(&token).unwrap().keyset().item_id, 42);
+        assert!(CursorKeys::new(&SessionKeys::new(new, &[]))
+            .decode(&token)
+            .is_none());
+    }
+}
@@ -0,0 +1,271 @@
+use crate::metrics::RoutePattern;
+use crate::AppState;
+use async_std::task;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tide::http::mime;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+const DEFAULT_PROFILE_SECS: u64 = 10;
+const DEFAULT_FREQUENCY: i32 = 99;
+/// Sampling rates pprof's timer can handle; 0 would divide by zero inside it.
+const MAX_FREQUENCY: i32 = 1000;
+/// Frames from these libraries are the signal machinery itself, not the program.
+const BLOCKLIST: &[&str] = &["libc", "libgcc", "pthread", "vdso"];
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Set once at startup when `debug.enabled` is on; until then the allocator only
+/// forwards to the system one.
+static COUNT_ALLOCATIONS: AtomicBool = AtomicBool::new(false);
+
+/// Keyed by (method, route pattern), like the HTTP metrics.
+static ALLOCATIONS: Lazy<Mutex<BTreeMap<(String, &'static str), AllocationStats>>> =
+    Lazy::new(Default::default);
+
+thread_local! {
+    static THREAD_ALLOCATIONS: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+/// The system allocator, also counting allocations and bytes per thread so
+/// [`AllocationCounter`] can attribute them to requests. Counting costs a thread-local
+/// update per allocation, so it stays off unless the debug endpoints are enabled.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn count_allocation(bytes: usize) {
+    if !COUNT_ALLOCATIONS.load(Ordering::Relaxed) {
+        return;
+    }
+    // `try_with` because the allocator keeps being used while thread locals are torn down.
+    let _ = THREAD_ALLOCATIONS.try_with(|counts| {
+        let (allocations, total) = counts.get();
+        counts.set((allocations + 1, total + bytes as u64));
+    });
+}
+
+fn thread_allocations() -> (u64, u64) {
+    THREAD_ALLOCATIONS.try_with(Cell::get).unwrap_or_default()
+}
+
+#[derive(Default)]
+struct AllocationStats {
+    requests: u64,
+    allocations: u64,
+    bytes: u64,
+}
+
+/// Attributes the allocations made while polling a future to that future, whichever
+/// worker thread each poll runs on. Resolves to the output plus `(allocations, bytes)`.
+struct Counted<F> {
+    inner: Pin<Box<F>>,
+    allocations: u64,
+    bytes: u64,
+}
+
+impl<F: Future> Future for Counted<F> {
+    type Output = (F::Output, u64, u64);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let (allocations, bytes) = thread_allocations();
+        let poll = this.inner.as_mut().poll(cx);
+        let (allocations_after, bytes_after) = thread_allocations();
+        this.allocations += allocations_after - allocations;
+        this.bytes += bytes_after - bytes;
+        poll.map(|output| (output, this.allocations, this.bytes))
+    }
+}
+
+/// Counts allocations per route pattern, served by `/debug/pprof/allocs`.
+pub(crate) struct AllocationCounter;
+
+impl AllocationCounter {
+    /// Turns on counting in the global allocator.
+    pub(crate) fn new() -> Self {
+        COUNT_ALLOCATIONS.store(true, Ordering::Relaxed);
+        AllocationCounter
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AllocationCounter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let method = req.method().to_string();
+        let (res, allocations, bytes) = Counted {
+            inner: Box::pin(next.run(req)),
+            allocations: 0,
+            bytes: 0,
+        }
+        .await;
+        let route = res
+            .ext::<RoutePattern>()
+            .map_or("unmatched", |route| route.0);
+
+        let mut registry = ALLOCATIONS.lock().unwrap();
+        let stats = registry.entry((method, route)).or_default();
+        stats.requests += 1;
+        stats.allocations += allocations;
+        stats.bytes += bytes;
+        Ok(res)
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    /// `flamegraph` (SVG, the default) or `collapsed` (folded stacks for flamegraph.pl,
+    /// inferno or speedscope).
+    format: Option<String>,
+}
+
+/// `GET /debug/pprof/profile?seconds=N`: samples the whole process for N seconds.
+/// Only one profile can run at a time.
+pub(crate) async fn get_profile(req: tide::Request<AppState>) -> tide::Result<Response> {
+    let query: ProfileQuery = req.query()?;
+    let max_secs = req.state().config.debug.max_profile_secs;
+    let seconds = query.seconds.unwrap_or(DEFAULT_PROFILE_SECS);
+    if seconds == 0 || seconds > max_secs {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("seconds must be between 1 and {}", max_secs),
+        ));
+    }
+    let frequency = query.frequency.unwrap_or(DEFAULT_FREQUENCY);
+    if !(1..=MAX_FREQUENCY).contains(&frequency) {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!("frequency must be between 1 and {}", MAX_FREQUENCY),
+        ));
+    }
+    let collapsed = match query.format.as_deref() {
+        None | Some("flamegraph") => false,
+        Some("collapsed") => true,
+        Some(other) => {
+            return Err(tide::Error::from_str(
+                StatusCode::BadRequest,
+                format!("unknown format `{}`", other),
+            ))
+        }
+    };
+
+    if PROFILING.swap(true, Ordering::SeqCst) {
+        return Err(tide::Error::from_str(
+            StatusCode::Conflict,
+            "a profile is already being taken",
+        ));
+    }
+    let _profiling = ProfilingGuard;
+    // The profiler guard must not be held across an await, so the whole capture runs on
+    // a blocking thread while the executor keeps serving the load being profiled.
+    let output =
+        task::spawn_blocking(move || capture(Duration::from_secs(seconds), frequency, collapsed))
+            .await;
+
+    let output = output.map_err(|e| tide::Error::new(StatusCode::InternalServerError, e))?;
+    if output.is_empty() {
+        // Nothing ran on a CPU during the whole window.
+        return Ok(Response::new(StatusCode::NoContent));
+    }
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(output);
+    res.set_content_type(if collapsed { mime::PLAIN } else { mime::SVG });
+    Ok(res)
+}
+
+/// Clears [`PROFILING`] when the request ends, even if the capture panicked.
+struct ProfilingGuard;
+
+impl Drop for ProfilingGuard {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+fn capture(duration: Duration, frequency: i32, collapsed: bool) -> pprof::Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(BLOCKLIST)
+        .build()?;
+    std::thread::sleep(duration);
+    let report = guard.report().build()?;
+
+    let mut output = Vec::new();
+    if collapsed {
+        let mut folded = String::new();
+        for (frames, count) in &report.data {
+            folded.push_str(&frames.thread_name_or_id());
+            for frame in frames.frames.iter().rev() {
+                for symbol in frame.iter().rev() {
+                    let _ = write!(folded, ";{}", symbol);
+                }
+            }
+            let _ = writeln!(folded, " {}", count);
+        }
+        output = folded.into_bytes();
+    } else {
+        report.flamegraph(&mut output)?;
+    }
+    Ok(output)
+}
+
+/// `GET /debug/pprof/allocs`: allocations per route since startup, heaviest first.
+pub(crate) async fn get_allocs(_req: tide::Request<AppState>) -> tide::Result<Response> {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    let mut rows: Vec<_> = allocations.iter().collect();
+    rows.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.bytes));
+
+    let mut out = String::from("method\troute\trequests\tallocations\tbytes\tbytes/request\n");
+    for ((method, route), stats) in rows {
+        let _ = writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            method,
+            route,
+            stats.requests,
+            stats.allocations,
+            stats.bytes,
+            stats.bytes / stats.requests.max(1)
+        );
+    }
+
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(out);
+    res.set_content_type(mime::PLAIN);
+    Ok(res)
+}
@@ -0,0 +1,106 @@
+use crate::config::{SecretsConfig, SecretsSource};
+use std::collections::HashMap;
+use std::{env, fmt, fs, io};
+use tide::StatusCode;
+
+/// A credential. `Debug` never shows the value and there is no `Display`, so it can't
+/// end up in a log line without an explicit [`expose`](Self::expose).
+#[derive(Clone)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(********)")
+    }
+}
+
+/// Credentials for the external services.
+#[derive(Clone, Debug)]
+pub(crate) struct Secrets {
+    /// Sent as `Authorization: Bearer <token>` to the shipment service.
+    pub(crate) shipment_api_token: Secret,
+}
+
+impl Secrets {
+    /// Reads each secret from the configured source:
+    ///
+    /// - `env`: `ISUCARI_SHIPMENT_API_TOKEN`.
+    /// - `file`: a TOML file with `shipment_api_token`, like `secrets.dev.toml`, which
+    ///   holds the benchmark's credentials for local runs.
+    /// - `dir`: a directory with one file per secret, named like the TOML keys, as
+    ///   mounted by Kubernetes or Docker secrets.
+    ///
+    /// There are no built-in fallbacks; a missing secret fails startup.
+    pub(crate) fn load(config: &SecretsConfig) -> tide::Result<Self> {
+        let source = Source::open(config)?;
+        let get = |name: &str| -> tide::Result<Secret> {
+            source.get(name)?.map(Secret).ok_or_else(|| {
+                secrets_error(format!(
+                    "secret `{}` is missing from the {} source; for local runs, use `--secrets.source file --secrets.path secrets.dev.toml`",
+                    name,
+                    config.source.as_str()
+                ))
+            })
+        };
+        Ok(Secrets {
+            shipment_api_token: get("shipment_api_token")?,
+        })
+    }
+}
+
+enum Source<'a> {
+    Env,
+    File(HashMap<String, String>),
+    Dir(&'a std::path::Path),
+}
+
+impl<'a> Source<'a> {
+    fn open(config: &'a SecretsConfig) -> tide::Result<Self> {
+        Ok(match config.source {
+            SecretsSource::Env => Source::Env,
+            SecretsSource::File => {
+                let text = fs::read_to_string(&config.path).map_err(|e| {
+                    secrets_error(format!("failed to read {}: {}", config.path.display(), e))
+                })?;
+                let values = toml::from_str(&text).map_err(|e| {
+                    secrets_error(format!("failed to parse {}: {}", config.path.display(), e))
+                })?;
+                Source::File(values)
+            }
+            SecretsSource::Dir => Source::Dir(&config.path),
+        })
+    }
+
+    fn get(&self, name: &str) -> tide::Result<Option<String>> {
+        let value = match self {
+            Source::Env => env::var(format!("ISUCARI_{}", name.to_ascii_uppercase())).ok(),
+            Source::File(values) => values.get(name).cloned(),
+            Source::Dir(dir) => {
+                let path = dir.join(name);
+                match fs::read_to_string(&path) {
+                    // Mounted secrets usually end with a newline.
+                    Ok(value) => Some(value.trim_end_matches(&['\r', '\n'][..]).to_string()),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => {
+                        return Err(secrets_error(format!(
+                            "failed to read {}: {}",
+                            path.display(),
+                            e
+                        )))
+                    }
+                }
+            }
+        };
+        Ok(value.filter(|value| !value.is_empty()))
+    }
+}
+
+fn secrets_error(message: String) -> tide::Error {
+    tide::Error::from_str(StatusCode::InternalServerError, message)
+}
@@ -0,0 +1,83 @@
+use crate::config::SecurityHeadersConfig;
+use crate::handlers::INDEX_HTML;
+use sha2::{Digest, Sha256};
+use tide::{Middleware, Next, Request};
+
+/// Adds the configured security headers to every response that doesn't set its own.
+/// `Strict-Transport-Security` is only sent on requests that came in over HTTPS.
+pub(crate) struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+    hsts: Option<String>,
+}
+
+impl SecurityHeaders {
+    pub(crate) fn new(config: &SecurityHeadersConfig) -> Self {
+        let csp = if config.content_security_policy.is_empty() {
+            default_csp()
+        } else {
+            config.content_security_policy.clone()
+        };
+        let mut headers = vec![
+            ("Content-Security-Policy", csp),
+            ("X-Content-Type-Options", "nosniff".to_string()),
+        ];
+        if !config.frame_options.is_empty() {
+            headers.push(("X-Frame-Options", config.frame_options.clone()));
+        }
+        if !config.referrer_policy.is_empty() {
+            headers.push(("Referrer-Policy", config.referrer_policy.clone()));
+        }
+        let hsts = Some(config.hsts_max_age_secs)
+            .filter(|&max_age| max_age > 0)
+            .map(|max_age| format!("max-age={}; includeSubDomains", max_age));
+        SecurityHeaders { headers, hsts }
+    }
+}
+
+/// A policy for the bundled React build: everything from our own origin, plus the
+/// inline webpack runtime in `index.html` by hash. Material-UI injects its styles at
+/// runtime, hence `'unsafe-inline'` for styles only; image previews on the sell page
+/// use `data:` and `blob:` URLs.
+fn default_csp() -> String {
+    let inline_scripts: String = inline_scripts(INDEX_HTML)
+        .map(|script| {
+            let hash = Sha256::digest(script.as_bytes());
+            format!(" 'sha256-{}'", base64::encode(hash))
+        })
+        .collect();
+    format!(
+        "default-src 'self'; script-src 'self'{}; style-src 'self' 'unsafe-inline'; \
+         img-src 'self' data: blob:; object-src 'none'; base-uri 'self'; \
+         form-action 'self'; frame-ancestors 'none'",
+        inline_scripts
+    )
+}
+
+/// Bodies of the `<script>` elements without a `src`.
+fn inline_scripts(html: &str) -> impl Iterator<Item = &str> {
+    html.split("<script").skip(1).filter_map(|element| {
+        let (attributes, rest) = element.split_at(element.find('>')?);
+        if attributes.contains("src=") {
+            return None;
+        }
+        let body = &rest[1..];
+        Some(&body[..body.find("</script>")?])
+    })
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for SecurityHeaders {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let https = req.url().scheme() == "https";
+        let mut res = next.run(req).await;
+        for &(name, ref value) in &self.headers {
+            if res.header(name).is_none() {
+                res.insert_header(name, value.as_str());
+            }
+        }
+        if let (true, Some(hsts)) = (https, &self.hsts) {
+            res.insert_header("Strict-Transport-Security", hsts.as_str());
+        }
+        Ok(res)
+    }
+}
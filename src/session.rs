@@ -0,0 +1,179 @@
+use crate::config::{SameSitePolicy, SessionConfig};
+use crate::consts;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tide::http::cookies::{Cookie, Key, SameSite};
+use tide::sessions::{Session, SessionStore};
+use tide::{Middleware, Next, Request, StatusCode};
+
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Length of the base64 HMAC-SHA256 prepended to the cookie value.
+const BASE64_DIGEST_LEN: usize = 44;
+
+/// The session secrets: the current one signs everything new, previous ones are still
+/// accepted so rotating the secret doesn't log everyone out.
+///
+/// A retired secret should stay in `session.previous_secrets` for at least a day, the
+/// lifetime of a session cookie.
+#[derive(Clone)]
+pub(crate) struct SessionKeys {
+    /// Current secret first.
+    secrets: Vec<Arc<[u8]>>,
+    signing: Vec<Key>,
+}
+
+impl SessionKeys {
+    /// Each secret must be at least 32 bytes, which the config validates.
+    pub(crate) fn new(current: &str, previous: &[String]) -> Self {
+        let secrets: Vec<Arc<[u8]>> = std::iter::once(current)
+            .chain(previous.iter().map(String::as_str))
+            .map(|secret| secret.as_bytes().into())
+            .collect();
+        let signing = secrets
+            .iter()
+            .map(|secret| Key::derive_from(secret))
+            .collect();
+        SessionKeys { secrets, signing }
+    }
+
+    /// A key per secret, current first, as `HMAC-SHA256(secret, domain)`. Each use of
+    /// the secrets passes its own `domain`, so the keys it gets are unrelated to any other
+    /// use's.
+    pub(crate) fn derive(&self, domain: &[u8]) -> Vec<Vec<u8>> {
+        self.secrets
+            .iter()
+            .map(|secret| {
+                let mut mac =
+                    Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+                mac.update(domain);
+                mac.finalize().into_bytes().to_vec()
+            })
+            .collect()
+    }
+
+    /// `base64(hmac) || value`, the same format as tide's session cookie, so cookies
+    /// issued before the switch stay valid.
+    fn sign(&self, value: &str) -> String {
+        let mut mac = mac(&self.signing[0]);
+        mac.update(value.as_bytes());
+        let mut signed = base64::encode(mac.finalize().into_bytes());
+        signed.push_str(value);
+        signed
+    }
+
+    /// The unsigned value, and whether it was signed with a previous secret.
+    fn verify(&self, signed: &str) -> Option<(String, bool)> {
+        if signed.len() < BASE64_DIGEST_LEN || !signed.is_char_boundary(BASE64_DIGEST_LEN) {
+            return None;
+        }
+        let (digest, value) = signed.split_at(BASE64_DIGEST_LEN);
+        let digest = base64::decode(digest).ok()?;
+        self.signing.iter().enumerate().find_map(|(i, key)| {
+            let mut mac = mac(key);
+            mac.update(value.as_bytes());
+            mac.verify(&digest)
+                .ok()
+                .map(|()| (value.to_string(), i > 0))
+        })
+    }
+}
+
+fn mac(key: &Key) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_varkey(key.signing()).expect("HMAC accepts keys of any length")
+}
+
+/// tide's session middleware, except that the `session_isucari` cookie may be signed
+/// with any of the [`SessionKeys`].
+///
+/// Sessions are only written when a handler changes them, so anonymous requests don't
+/// create rows or cookies. Sessions signed with a previous secret are written once more
+/// to re-sign them with the current one.
+pub(crate) struct SessionMiddleware<Store> {
+    store: Store,
+    keys: SessionKeys,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl<Store: SessionStore> SessionMiddleware<Store> {
+    pub(crate) fn new(store: Store, keys: SessionKeys, config: &SessionConfig) -> Self {
+        SessionMiddleware {
+            store,
+            keys,
+            secure: config.cookie_secure,
+            same_site: match config.cookie_same_site {
+                SameSitePolicy::Strict => SameSite::Strict,
+                SameSitePolicy::Lax => SameSite::Lax,
+                SameSitePolicy::None => SameSite::None,
+            },
+        }
+    }
+
+    fn build_cookie(&self, value: &str, https: bool) -> Cookie<'static> {
+        let mut cookie = Cookie::build(consts::SESSION_NAME, self.keys.sign(value))
+            .http_only(true)
+            .same_site(self.same_site)
+            .secure(self.secure || https)
+            .path("/")
+            .finish();
+        cookie.set_expires(Some((SystemTime::now() + SESSION_TTL).into()));
+        cookie
+    }
+}
+
+#[tide::utils::async_trait]
+impl<Store, State> Middleware<State> for SessionMiddleware<Store>
+where
+    Store: SessionStore,
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let cookie = req.cookie(consts::SESSION_NAME);
+        let verified = cookie
+            .as_ref()
+            .and_then(|cookie| self.keys.verify(cookie.value()));
+        let loaded = match &verified {
+            Some((value, _)) => self
+                .store
+                .load_session(value.clone())
+                .await
+                .ok()
+                .flatten()
+                .and_then(Session::validate),
+            None => None,
+        };
+        let rotated = loaded.is_some() && matches!(verified, Some((_, true)));
+        let mut session = loaded.unwrap_or_default();
+        session.expire_in(SESSION_TTL);
+
+        let https = req.url().scheme() == "https";
+        req.set_ext(session.clone());
+        let mut res = next.run(req).await;
+
+        if session.is_destroyed() {
+            if let Err(e) = self.store.destroy_session(session).await {
+                tide::log::error!("unable to destroy session", { error: e.to_string() });
+            }
+            if let Some(mut cookie) = cookie {
+                cookie.set_path("/");
+                res.remove_cookie(cookie);
+            }
+        } else if rotated || session.data_changed() {
+            if let (true, Some((value, _))) = (rotated, verified) {
+                session.set_cookie_value(value);
+            }
+            let stored = self
+                .store
+                .store_session(session)
+                .await
+                .map_err(|e| tide::Error::from_str(StatusCode::InternalServerError, e))?;
+            if let Some(value) = stored {
+                res.insert_cookie(self.build_cookie(&value, https));
+            }
+        }
+        Ok(res)
+    }
+}
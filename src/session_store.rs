@@ -0,0 +1,203 @@
+use crate::db::{self, Db};
+use crate::session::SessionKeys;
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use async_session::{async_trait, Error, Result, Session, SessionStore};
+use async_std::task;
+use chrono::Utc;
+use rand::RngCore;
+use sqlx::Done;
+use std::fmt;
+use std::time::Duration;
+
+const ENCRYPTION_KEY_DOMAIN: &[u8] = b"isucari-session-encryption:";
+const NONCE_LEN: usize = 12;
+
+/// Sessions persisted in the `sessions` table, so logins survive restarts and are
+/// shared by every instance behind a load balancer.
+///
+/// Expired rows are never loaded; [`spawn_cleanup`](Self::spawn_cleanup) deletes them.
+#[derive(Clone)]
+pub(crate) struct MySqlStore {
+    db: Db,
+}
+
+impl fmt::Debug for MySqlStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MySqlStore").finish()
+    }
+}
+
+impl MySqlStore {
+    pub(crate) fn new(db: Db) -> Self {
+        MySqlStore { db }
+    }
+
+    /// Deletes expired sessions, returning how many were removed.
+    pub(crate) async fn cleanup(&self) -> sqlx::Result<u64> {
+        let done = sqlx::query("DELETE FROM `sessions` WHERE `expires` < ?")
+            .bind(Utc::now())
+            .execute(self.db.pool())
+            .await?;
+        Ok(done.rows_affected())
+    }
+
+    /// Runs [`cleanup`](Self::cleanup) every `interval` for the life of the process.
+    pub(crate) fn spawn_cleanup(&self, interval: Duration) {
+        let store = self.clone();
+        task::spawn(async move {
+            loop {
+                task::sleep(interval).await;
+                match store.cleanup().await {
+                    Ok(0) => {}
+                    Ok(deleted) => {
+                        tide::log::debug!("deleted expired sessions", { count: deleted })
+                    }
+                    Err(e) => {
+                        tide::log::warn!("session cleanup failed", { error: e.to_string() })
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl SessionStore for MySqlStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        let row: Option<(String,)> = db::timed(
+            "SELECT `session` FROM `sessions` WHERE `id` = ? AND (`expires` IS NULL OR `expires` > ?)",
+            |sql| {
+                sqlx::query_as(sql)
+                    .bind(&id)
+                    .bind(Utc::now())
+                    .fetch_optional(self.db.pool())
+            },
+        )
+        .await?;
+
+        match row {
+            Some((json,)) => Ok(serde_json::from_str::<Session>(&json)?.validate()),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let json = serde_json::to_string(&session)?;
+        db::timed(
+            r"
+            INSERT INTO `sessions` (`id`, `session`, `expires`) VALUES (?, ?, ?)
+            ON DUPLICATE KEY UPDATE `session` = VALUES(`session`), `expires` = VALUES(`expires`)
+            ",
+            |sql| {
+                sqlx::query(sql)
+                    .bind(session.id())
+                    .bind(json)
+                    .bind(session.expiry().copied())
+                    .execute(self.db.pool())
+            },
+        )
+        .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        db::timed("DELETE FROM `sessions` WHERE `id` = ?", |sql| {
+            sqlx::query(sql).bind(session.id()).execute(self.db.pool())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        db::timed("DELETE FROM `sessions`", |sql| {
+            sqlx::query(sql).execute(self.db.pool())
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Sessions kept entirely in the cookie: the serialized session is encrypted with
+/// AES-256-GCM, then signed by the session middleware like any other cookie value.
+///
+/// Nothing is stored server-side, so instances share no state. The flip side is that
+/// logging out only drops the cookie from the browser; `/sessions/revoke_all` still
+/// works, since the session generation is checked against `users`.
+#[derive(Clone)]
+pub(crate) struct EncryptedCookieStore {
+    /// One per session secret, current first; only the current one encrypts.
+    ciphers: Vec<Aes256Gcm>,
+}
+
+impl fmt::Debug for EncryptedCookieStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedCookieStore").finish()
+    }
+}
+
+impl EncryptedCookieStore {
+    /// Derives an encryption key from each session secret.
+    pub(crate) fn new(keys: &SessionKeys) -> Self {
+        let ciphers = keys
+            .derive(ENCRYPTION_KEY_DOMAIN)
+            .iter()
+            .map(|key| Aes256Gcm::new(GenericArray::from_slice(key)))
+            .collect();
+        EncryptedCookieStore { ciphers }
+    }
+
+    /// `base64url(nonce || ciphertext)`.
+    fn seal(&self, plaintext: &[u8]) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self.ciphers[0]
+            .encrypt(GenericArray::from_slice(&nonce), plaintext)
+            .map_err(|_| Error::msg("failed to encrypt session"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(base64::encode_config(sealed, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn open(&self, cookie_value: &str) -> Option<Vec<u8>> {
+        let sealed = base64::decode_config(cookie_value, base64::URL_SAFE_NO_PAD).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.ciphers.iter().find_map(|cipher| {
+            cipher
+                .decrypt(GenericArray::from_slice(nonce), ciphertext)
+                .ok()
+        })
+    }
+}
+
+#[async_trait]
+impl SessionStore for EncryptedCookieStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        match self.open(&cookie_value) {
+            Some(json) => Ok(serde_json::from_slice::<Session>(&json)?.validate()),
+            None => Ok(None),
+        }
+    }
+
+    /// Always hands back a fresh cookie value: the cookie is the only copy.
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let json = serde_json::to_vec(&session)?;
+        session.reset_data_changed();
+        self.seal(&json).map(Some)
+    }
+
+    async fn destroy_session(&self, _session: Session) -> Result {
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use async_std::task;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::http::headers::CONNECTION;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Resolves with the signal number once SIGTERM or SIGINT arrives.
+///
+/// The handlers are installed before this returns, so a signal sent while the server is
+/// still starting up is not lost.
+pub(crate) fn signal() -> io::Result<impl Future<Output = i32>> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    Ok(
+        async move { task::spawn_blocking(move || signals.forever().next().unwrap_or(SIGTERM)).await },
+    )
+}
+
+/// Counts requests that are still being handled so shutdown can wait for them.
+///
+/// Once draining has started, requests arriving on kept-alive connections are turned
+/// away with 503 instead of starting work that may be cut off halfway.
+#[derive(Clone, Default)]
+pub(crate) struct InFlight {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    count: AtomicUsize,
+    draining: AtomicBool,
+}
+
+struct Guard<'a>(&'a Inner);
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlight {
+    pub(crate) fn count(&self) -> usize {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+
+    /// Stops admitting requests and waits for the running ones to finish.
+    /// Returns the number of requests still running when `timeout` ran out.
+    pub(crate) async fn drain(&self, timeout: Duration) -> usize {
+        self.inner.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        while self.count() > 0 && Instant::now() < deadline {
+            task::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        self.count()
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for InFlight {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+        let _guard = Guard(&self.inner);
+        if self.inner.draining.load(Ordering::SeqCst) {
+            let mut res = Response::new(StatusCode::ServiceUnavailable);
+            res.insert_header(CONNECTION, "close");
+            return Ok(res);
+        }
+        Ok(next.run(req).await)
+    }
+}
@@ -0,0 +1,252 @@
+use crate::config::TlsConfig;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::{io, task};
+use futures_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use futures_rustls::rustls::sign::CertifiedKey;
+use futures_rustls::rustls::{crypto, ServerConfig};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tide::listener::{Listener, ToListener};
+use tide::Server;
+
+/// Clients that haven't finished the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate chain and key from `[tls]`, swapped in place on reload so that new
+/// handshakes pick them up while established connections carry on.
+pub(crate) struct Certificates {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub(crate) fn load(config: &TlsConfig) -> io::Result<Arc<Self>> {
+        let current = read_certified_key(&config.cert_file, &config.key_file)?;
+        Ok(Arc::new(Certificates {
+            cert_file: config.cert_file.clone(),
+            key_file: config.key_file.clone(),
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    /// Reads both files again. On failure the previous certificate stays in use.
+    pub(crate) fn reload(&self) -> io::Result<()> {
+        let reloaded = read_certified_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap() = Arc::new(reloaded);
+        Ok(())
+    }
+
+    /// Reloads on every SIGHUP, e.g. after a renewal has replaced the files.
+    pub(crate) fn spawn_reload_on_sighup(self: &Arc<Self>) -> io::Result<()> {
+        let mut signals = Signals::new([SIGHUP])?;
+        let certificates = self.clone();
+        task::spawn_blocking(move || {
+            for _ in signals.forever() {
+                match certificates.reload() {
+                    Ok(()) => tide::log::info!("reloaded TLS certificate", {
+                        cert_file: certificates.cert_file.display().to_string(),
+                    }),
+                    Err(e) => {
+                        tide::log::error!("failed to reload TLS certificate, keeping the old one", {
+                            error: e.to_string(),
+                        })
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificates")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// A PEM certificate chain, leaf first, and a PEM private key (PKCS#8, PKCS#1 or SEC1).
+fn read_certified_key(cert_file: &Path, key_file: &Path) -> io::Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path).map(std::io::BufReader::new).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read {}: {}", path.display(), e),
+            )
+        })
+    };
+    let invalid = |path: &Path, message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_file)?)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| invalid(cert_file, e.to_string()))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_file, "no certificates found".to_string()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_file)?)
+        .map_err(|e| invalid(key_file, e.to_string()))?
+        .ok_or_else(|| invalid(key_file, "no private key found".to_string()))?;
+    let key = crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| invalid(key_file, e.to_string()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Serves HTTPS on a TCP address, for `tls:host:port` in `server.listen`.
+pub(crate) struct TlsListener {
+    addr: String,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsListener {
+    pub(crate) fn new(addr: &str, certificates: &Arc<Certificates>) -> Self {
+        TlsListener {
+            addr: addr.to_string(),
+            acceptor: certificates.acceptor(),
+        }
+    }
+}
+
+impl fmt::Debug for TlsListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl fmt::Display for TlsListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}", self.addr)
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for TlsListener {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for TlsListener {
+    async fn listen(&mut self, app: Server<State>) -> io::Result<()> {
+        let listener = TcpListener::bind(self.addr.as_str()).await?;
+        tide::log::info!("Server listening on https://{}", listener.local_addr()?);
+
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => handle_tls(app.clone(), self.acceptor.clone(), stream),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::ConnectionReset
+                    ) => {}
+                Err(e) => {
+                    let delay = Duration::from_millis(500);
+                    tide::log::error!("Error: {}. Pausing for {:?}.", e, delay);
+                    task::sleep(delay).await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn handle_tls<State: Clone + Send + Sync + 'static>(
+    app: Server<State>,
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+) {
+    task::spawn(async move {
+        let local_addr = stream.local_addr().ok();
+        let peer_addr = stream.peer_addr().ok();
+        let stream = match io::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(stream) => SharedStream(Arc::new(Mutex::new(stream))),
+            Err(e) => {
+                tide::log::debug!("TLS handshake failed", { error: e.to_string() });
+                return;
+            }
+        };
+
+        let fut = async_h1::accept(stream, |mut req| async {
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            // async-h1 assumes `http`; the scheme is how the session cookie and HSTS
+            // know the request came in over TLS.
+            let _ = req.url_mut().set_scheme("https");
+            app.respond(req).await
+        });
+        if let Err(error) = fut.await {
+            tide::log::error!("async-h1 error", { error: error.to_string() });
+        }
+    });
+}
+
+/// async-h1 needs a stream it can clone to read the request body while writing the
+/// response; a TLS session can't be split, so the halves share it.
+#[derive(Clone)]
+struct SharedStream(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl io::Read for SharedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl io::Write for SharedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+    }
+}
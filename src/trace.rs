@@ -0,0 +1,403 @@
+use crate::access_log;
+use crate::config::{TraceConfig, TraceExporter};
+use crate::metrics::RoutePattern;
+use async_channel::{Receiver, Sender, TrySendError};
+use async_std::fs::OpenOptions;
+use async_std::io::prelude::WriteExt;
+use async_std::{future, task, task_local};
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::{Middleware, Next, Request};
+
+/// W3C Trace Context header, understood by OpenTelemetry SDKs and collectors.
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+
+const QUEUE_CAPACITY: usize = 4096;
+const BATCH_SIZE: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+static EXPORTER: OnceCell<Sender<Message>> = OnceCell::new();
+
+task_local! {
+    static CURRENT: Cell<Option<SpanContext>> = Cell::new(None);
+}
+
+enum Message {
+    Span(SpanData),
+    Flush(Sender<()>),
+}
+
+/// Identifies a span within its trace.
+#[derive(Clone, Copy)]
+pub(crate) struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl SpanContext {
+    fn new_root() -> Self {
+        let mut trace_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut trace_id);
+        SpanContext {
+            trace_id,
+            span_id: new_span_id(),
+        }
+    }
+
+    fn child(&self) -> Self {
+        SpanContext {
+            trace_id: self.trace_id,
+            span_id: new_span_id(),
+        }
+    }
+
+    /// Reads a `traceparent` value such as `00-<32 hex>-<16 hex>-01`.
+    fn parse_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        if parts.next()? != "00" {
+            return None;
+        }
+        let mut context = SpanContext {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+        };
+        decode_hex(parts.next()?, &mut context.trace_id)?;
+        decode_hex(parts.next()?, &mut context.span_id)?;
+        parts.next()?;
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return None;
+        }
+        Some(context)
+    }
+
+    pub(crate) fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-01",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id)
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum SpanKind {
+    Server,
+    Client,
+}
+
+/// A span that is exported when dropped. Inert when tracing is off or the current task
+/// isn't handling a traced request, so call sites don't need to check.
+pub(crate) struct Span {
+    data: Option<SpanData>,
+}
+
+struct SpanData {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Starts a child of the span the current task is working under.
+    pub(crate) fn child(name: impl Into<String>, kind: SpanKind) -> Self {
+        let parent = match current() {
+            Some(parent) if EXPORTER.get().is_some() => parent,
+            _ => return Span { data: None },
+        };
+        Span {
+            data: Some(SpanData {
+                context: parent.child(),
+                parent_span_id: Some(parent.span_id),
+                name: name.into(),
+                kind,
+                start: SystemTime::now(),
+                end: None,
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    /// The `traceparent` value to send along with an outbound call made under this span.
+    pub(crate) fn traceparent(&self) -> Option<String> {
+        self.data.as_ref().map(|data| data.context.traceparent())
+    }
+
+    pub(crate) fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub(crate) fn record_result<T, E: Display>(&mut self, result: &Result<T, E>) {
+        if let (Some(data), Err(e)) = (&mut self.data, result) {
+            data.error = Some(e.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(mut data), Some(exporter)) = (self.data.take(), EXPORTER.get()) {
+            data.end.get_or_insert_with(SystemTime::now);
+            if let Err(TrySendError::Full(_)) = exporter.try_send(Message::Span(data)) {
+                tide::log::debug!("trace export queue is full; dropping a span");
+            }
+        }
+    }
+}
+
+fn current() -> Option<SpanContext> {
+    CURRENT.try_with(Cell::get).ok().flatten()
+}
+
+/// Opens a server span per request, continuing the caller's trace when it sent a
+/// `traceparent`. The span is named after the route pattern, e.g.
+/// `GET /items/:item_id.json`.
+pub(crate) struct Tracing;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Tracing {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let remote = req
+            .header(TRACEPARENT_HEADER)
+            .and_then(|values| SpanContext::parse_traceparent(values.last().as_str()));
+        let context = remote.map_or_else(SpanContext::new_root, |remote| remote.child());
+        let method = req.method().to_string();
+        let mut span = Span {
+            data: Some(SpanData {
+                context,
+                parent_span_id: remote.map(|remote| remote.span_id),
+                name: method.clone(),
+                kind: SpanKind::Server,
+                start: SystemTime::now(),
+                end: None,
+                attributes: vec![
+                    ("http.method", method.clone().into()),
+                    ("http.target", req.url().path().into()),
+                ],
+                error: None,
+            }),
+        };
+        if let Some(request_id) = access_log::current_request_id() {
+            span.set_attribute("http.request_id", request_id);
+        }
+
+        let previous = CURRENT.with(|current| current.replace(Some(context)));
+        let res = next.run(req).await;
+        CURRENT.with(|current| current.set(previous));
+
+        let status = res.status();
+        span.set_attribute("http.status_code", u16::from(status));
+        if let Some(RoutePattern(route)) = res.ext::<RoutePattern>() {
+            span.set_attribute("http.route", *route);
+            if let Some(data) = &mut span.data {
+                data.name = format!("{} {}", method, route);
+            }
+        }
+        if status.is_server_error() {
+            if let Some(data) = &mut span.data {
+                data.error = Some(
+                    res.error()
+                        .map_or_else(|| status.to_string(), |e| e.to_string()),
+                );
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Starts the background exporter. With `trace.exporter = "none"` nothing is recorded
+/// and [`Tracing`] should not be installed.
+pub(crate) fn start(config: &TraceConfig) {
+    let sink = match config.exporter {
+        TraceExporter::None => return,
+        TraceExporter::Otlp => Sink::Otlp(config.otlp_endpoint.clone()),
+        TraceExporter::File => Sink::File(config.file.clone()),
+    };
+    let (sender, receiver) = async_channel::bounded(QUEUE_CAPACITY);
+    if EXPORTER.set(sender).is_ok() {
+        task::spawn(export_loop(sink, config.service_name.clone(), receiver));
+    }
+}
+
+/// Waits until every span recorded so far has been handed to the exporter.
+pub(crate) async fn flush() {
+    if let Some(exporter) = EXPORTER.get() {
+        let (done, wait) = async_channel::bounded(1);
+        if exporter.send(Message::Flush(done)).await.is_ok() {
+            let _ = wait.recv().await;
+        }
+    }
+}
+
+enum Sink {
+    Otlp(String),
+    File(PathBuf),
+}
+
+async fn export_loop(sink: Sink, service_name: String, receiver: Receiver<Message>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Ok(message) = receiver.recv().await {
+        let mut flushed = Vec::new();
+        let deadline = Instant::now() + BATCH_DELAY;
+        let mut next = Some(message);
+        while let Some(message) = next.take() {
+            match message {
+                Message::Span(span) => batch.push(span),
+                Message::Flush(done) => {
+                    flushed.push(done);
+                    break;
+                }
+            }
+            if batch.len() >= BATCH_SIZE {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            next = future::timeout(remaining, receiver.recv())
+                .await
+                .ok()
+                .and_then(Result::ok);
+        }
+
+        if !batch.is_empty() {
+            if let Err(e) = export(&sink, &service_name, &batch).await {
+                tide::log::warn!("failed to export {} spans: {}", batch.len(), e);
+            }
+            batch.clear();
+        }
+        for done in flushed {
+            let _ = done.send(()).await;
+        }
+    }
+}
+
+async fn export(sink: &Sink, service_name: &str, batch: &[SpanData]) -> tide::Result<()> {
+    let payload = otlp_payload(service_name, batch);
+    match sink {
+        Sink::Otlp(endpoint) => {
+            let res = surf::post(endpoint).body_json(&payload)?.await?;
+            if !res.status().is_success() {
+                return Err(tide::Error::from_str(
+                    res.status(),
+                    format!("collector answered {}", res.status()),
+                ));
+            }
+        }
+        Sink::File(path) => {
+            let mut line = serde_json::to_vec(&payload)?;
+            line.push(b'\n');
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&line).await?;
+        }
+    }
+    Ok(())
+}
+
+/// An OTLP/HTTP JSON `ExportTraceServiceRequest`. The file exporter writes one per line,
+/// which is the format of the collector's own file exporter.
+fn otlp_payload(service_name: &str, batch: &[SpanData]) -> Value {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct OtlpSpan {
+        trace_id: String,
+        span_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        parent_span_id: Option<String>,
+        name: String,
+        kind: u8,
+        start_time_unix_nano: String,
+        end_time_unix_nano: String,
+        attributes: Vec<Value>,
+        status: Value,
+    }
+
+    let spans: Vec<OtlpSpan> = batch
+        .iter()
+        .map(|span| OtlpSpan {
+            trace_id: encode_hex(&span.context.trace_id),
+            span_id: encode_hex(&span.context.span_id),
+            parent_span_id: span.parent_span_id.as_ref().map(|id| encode_hex(id)),
+            name: span.name.clone(),
+            kind: match span.kind {
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            start_time_unix_nano: unix_nanos(span.start),
+            end_time_unix_nano: unix_nanos(span.end.unwrap_or(span.start)),
+            attributes: span
+                .attributes
+                .iter()
+                .map(|(key, value)| otlp_attribute(key, value))
+                .collect(),
+            status: match &span.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({}),
+            },
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [otlp_attribute("service.name", &service_name.into())],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn otlp_attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+        .to_string()
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut span_id = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut span_id);
+    span_id
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str, out: &mut [u8]) -> Option<()> {
+    if hex.len() != out.len() * 2 {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(())
+}